@@ -0,0 +1,182 @@
+use std::{marker::PhantomData, ops::Deref};
+
+use crate::prelude::*;
+
+/// Transaction that is aborted and released when dropped unless it was committed.
+///
+/// Dereferences to the transaction handle, so it can be passed to the methods of
+/// `TxnStorageTrait`. `commit` and `abort` consume the guard, and iterators borrow it,
+/// so a transaction cannot be finished while one of its iterators is alive:
+///
+/// ```compile_fail
+/// # use txn_storage::prelude::*;
+/// # let storage = InMemStorage::new();
+/// # let db_id = storage.open_db(DBOptions::new("db")).unwrap();
+/// # let txn = TxnGuard::begin(&storage, &db_id, TxnOptions::default()).unwrap();
+/// # let c_id = storage.create_container(&txn, &db_id, ContainerOptions::new("c", ContainerType::BTree)).unwrap();
+/// let iter = txn.scan(&c_id, ScanOptions::new()).unwrap();
+/// txn.commit(false).unwrap(); // error: txn is borrowed by iter
+/// drop(iter);
+/// ```
+pub struct TxnGuard<'a, T: TxnStorageTrait> {
+    storage: &'a T,
+    handle: Option<T::TxnHandle>, // None once committed or aborted
+}
+
+impl<'a, T: TxnStorageTrait> TxnGuard<'a, T> {
+    pub fn begin(
+        storage: &'a T,
+        db_id: &DatabaseId,
+        options: TxnOptions,
+    ) -> Result<Self, StorageError> {
+        let handle = storage.begin_txn(db_id, options)?;
+        Ok(TxnGuard {
+            storage,
+            handle: Some(handle),
+        })
+    }
+
+    /// Begins a read-only snapshot. See `TxnStorageTrait::begin_snapshot`.
+    pub fn snapshot(storage: &'a T, db_id: &DatabaseId) -> Result<Self, StorageError> {
+        let handle = storage.begin_snapshot(db_id)?;
+        Ok(TxnGuard {
+            storage,
+            handle: Some(handle),
+        })
+    }
+
+    /// Commits and releases the transaction. It is aborted if the commit fails.
+    pub fn commit(mut self, async_commit: bool) -> Result<(), StorageError> {
+        let handle = self.handle.take().unwrap();
+        let result = self.storage.commit_txn(&handle, async_commit);
+        if result.is_err() {
+            let _ = self.storage.abort_txn(&handle);
+        }
+        // The outcome of the transaction is decided; failing to release its handle does
+        // not change it
+        let _ = self.storage.drop_txn(handle);
+        result
+    }
+
+    /// Aborts and releases the transaction.
+    pub fn abort(mut self) -> Result<(), StorageError> {
+        let handle = self.handle.take().unwrap();
+        let result = self.storage.abort_txn(&handle);
+        let _ = self.storage.drop_txn(handle);
+        result
+    }
+
+    /// Scans the container. The iterator is released when dropped.
+    pub fn scan(
+        &self,
+        c_id: &ContainerId,
+        options: ScanOptions,
+    ) -> Result<IteratorGuard<'_, T>, StorageError> {
+        let handle = self.storage.scan_range(self, c_id, options)?;
+        Ok(IteratorGuard {
+            storage: self.storage,
+            handle: Some(handle),
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<T: TxnStorageTrait> Deref for TxnGuard<'_, T> {
+    type Target = T::TxnHandle;
+
+    fn deref(&self) -> &T::TxnHandle {
+        self.handle.as_ref().unwrap()
+    }
+}
+
+impl<T: TxnStorageTrait> Drop for TxnGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = self.storage.abort_txn(&handle);
+            let _ = self.storage.drop_txn(handle);
+        }
+    }
+}
+
+/// Iterator of a `TxnGuard` that is released when dropped. It borrows the transaction,
+/// so it cannot outlive it.
+pub struct IteratorGuard<'t, T: TxnStorageTrait> {
+    storage: &'t T,
+    handle: Option<T::IteratorHandle>, // taken when dropped
+    phantom: PhantomData<&'t T::TxnHandle>,
+}
+
+impl<T: TxnStorageTrait> Iterator for IteratorGuard<'_, T> {
+    type Item = Result<(Vec<u8>, Vec<u8>), StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.storage
+            .iter_next(self.handle.as_ref().unwrap())
+            .transpose()
+    }
+}
+
+impl<T: TxnStorageTrait> Drop for IteratorGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = self.storage.drop_iterator_handle(handle);
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    fn setup(c_type: ContainerType) -> (InMemStorage, DatabaseId, ContainerId) {
+        let storage = InMemStorage::new();
+        let db_id = storage.open_db(DBOptions::new("test_db")).unwrap();
+        let txn = TxnGuard::begin(&storage, &db_id, TxnOptions::default()).unwrap();
+        let options = ContainerOptions::new("test_container", c_type);
+        let c_id = storage.create_container(&txn, &db_id, options).unwrap();
+        txn.commit(false).unwrap();
+        (storage, db_id, c_id)
+    }
+
+    #[test]
+    fn test_txn_guard() {
+        let (storage, db_id, c_id) = setup(ContainerType::Hash);
+        let txn = TxnGuard::begin(&storage, &db_id, TxnOptions::default()).unwrap();
+        storage.insert_value(&txn, &c_id, vec![0], vec![1]).unwrap();
+        txn.commit(false).unwrap();
+
+        let txn = TxnGuard::begin(&storage, &db_id, TxnOptions::default()).unwrap();
+        assert_eq!(storage.get_value(&txn, &c_id, [0]), Ok(vec![1]));
+        txn.abort().unwrap();
+    }
+
+    #[test]
+    fn test_dropped_txn_guard_aborts() {
+        let (storage, db_id, c_id) = setup(ContainerType::Hash);
+        {
+            let txn = TxnGuard::begin(&storage, &db_id, TxnOptions::default()).unwrap();
+            storage.insert_value(&txn, &c_id, vec![0], vec![1]).unwrap();
+        }
+        assert!(storage.active_transactions().is_empty());
+        let txn = TxnGuard::begin(&storage, &db_id, TxnOptions::default()).unwrap();
+        let err = storage.get_value(&txn, &c_id, [0]).unwrap_err();
+        assert_eq!(err, Status::KeyNotFound);
+    }
+
+    #[test]
+    fn test_iterator_guard_releases_latch() {
+        let (storage, db_id, c_id) = setup(ContainerType::BTree);
+        let txn = TxnGuard::begin(&storage, &db_id, TxnOptions::default()).unwrap();
+        for i in 0..4 {
+            storage.insert_value(&txn, &c_id, vec![i], vec![i]).unwrap();
+        }
+        {
+            let iter = txn.scan(&c_id, ScanOptions::new()).unwrap();
+            let keys: Vec<_> = iter.map(|r| r.unwrap().0).collect();
+            assert_eq!(keys, [[0], [1], [2], [3]]);
+        }
+        // The iterator released the latch of the container, so writers can proceed
+        storage.insert_value(&txn, &c_id, vec![4], vec![4]).unwrap();
+        txn.commit(false).unwrap();
+    }
+}
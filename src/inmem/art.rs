@@ -0,0 +1,759 @@
+use std::cmp::Ordering;
+
+use super::Entry;
+
+/// Adaptive Radix Tree mapping byte-string keys to entries, in key order.
+///
+/// Inner nodes store the compressed path shared by their keys and leaves only store the
+/// rest of their key, so long common prefixes (e.g. tenant/table/row paths) are stored
+/// once and compared once. Inner nodes grow and shrink between 4, 16, 48 and 256
+/// children with the number of distinct next bytes, and each size is allocated
+/// separately so that small nodes stay small. A key that ends at an inner node (a prefix
+/// of other keys) is stored in the node itself.
+#[derive(Default)]
+pub struct Art {
+    root: Option<Node>,
+}
+
+enum Node {
+    Leaf(Box<Leaf>),
+    Inner(Box<Inner>),
+}
+
+struct Leaf {
+    key: Box<[u8]>, // the rest of the key below the node holding the leaf
+    entry: Entry,
+}
+
+// Invariant: an inner node holds at least two keys (its end leaf and its children).
+struct Inner {
+    prefix: Box<[u8]>,      // compressed path below the byte that leads to this node
+    end: Option<Box<Leaf>>, // the key that ends at this node, with an empty rest
+    children: Children,
+}
+
+enum Children {
+    Node4(Box<Sorted<4>>),
+    Node16(Box<Sorted<16>>),
+    Node48(Box<Node48>),
+    Node256(Box<Node256>),
+}
+
+// Children with their bytes kept in sorted arrays
+struct Sorted<const N: usize> {
+    len: u8,
+    keys: [u8; N],
+    children: [Option<Node>; N],
+}
+
+struct Node48 {
+    len: u8,
+    index: [u8; 256], // slot + 1 of the child of each byte, 0 if none
+    children: [Option<Node>; 48],
+}
+
+struct Node256 {
+    len: u16,
+    children: [Option<Node>; 256],
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+impl Leaf {
+    fn new(key: &[u8], entry: Entry) -> Box<Self> {
+        Box::new(Leaf {
+            key: key.into(),
+            entry,
+        })
+    }
+}
+
+impl<const N: usize> Sorted<N> {
+    fn new() -> Box<Self> {
+        Box::new(Sorted {
+            len: 0,
+            keys: [0; N],
+            children: std::array::from_fn(|_| None),
+        })
+    }
+
+    fn position(&self, byte: u8) -> Result<usize, usize> {
+        self.keys[..self.len as usize].binary_search(&byte)
+    }
+
+    fn add(&mut self, byte: u8, node: Node) {
+        let pos = self.position(byte).unwrap_err();
+        let len = self.len as usize;
+        self.keys[len] = byte;
+        self.children[len] = Some(node);
+        self.keys[pos..=len].rotate_right(1);
+        self.children[pos..=len].rotate_right(1);
+        self.len += 1;
+    }
+
+    fn remove(&mut self, byte: u8) -> Option<Node> {
+        let pos = self.position(byte).ok()?;
+        let node = self.children[pos].take();
+        let len = self.len as usize;
+        self.keys[pos..len].rotate_left(1);
+        self.children[pos..len].rotate_left(1);
+        self.len -= 1;
+        node
+    }
+}
+
+impl Node48 {
+    fn new() -> Box<Self> {
+        Box::new(Node48 {
+            len: 0,
+            index: [0; 256],
+            children: std::array::from_fn(|_| None),
+        })
+    }
+}
+
+impl Node256 {
+    fn new() -> Box<Self> {
+        Box::new(Node256 {
+            len: 0,
+            children: std::array::from_fn(|_| None),
+        })
+    }
+}
+
+impl Children {
+    fn len(&self) -> usize {
+        match self {
+            Children::Node4(n) => n.len as usize,
+            Children::Node16(n) => n.len as usize,
+            Children::Node48(n) => n.len as usize,
+            Children::Node256(n) => n.len as usize,
+        }
+    }
+
+    fn find(&self, byte: u8) -> Option<&Node> {
+        match self {
+            Children::Node4(n) => n.position(byte).ok().and_then(|i| n.children[i].as_ref()),
+            Children::Node16(n) => n.position(byte).ok().and_then(|i| n.children[i].as_ref()),
+            Children::Node48(n) => match n.index[byte as usize] {
+                0 => None,
+                slot => n.children[slot as usize - 1].as_ref(),
+            },
+            Children::Node256(n) => n.children[byte as usize].as_ref(),
+        }
+    }
+
+    fn find_mut(&mut self, byte: u8) -> Option<&mut Node> {
+        match self {
+            Children::Node4(n) => n.position(byte).ok().and_then(|i| n.children[i].as_mut()),
+            Children::Node16(n) => n.position(byte).ok().and_then(|i| n.children[i].as_mut()),
+            Children::Node48(n) => match n.index[byte as usize] {
+                0 => None,
+                slot => n.children[slot as usize - 1].as_mut(),
+            },
+            Children::Node256(n) => n.children[byte as usize].as_mut(),
+        }
+    }
+
+    // Removes all the children in byte order
+    fn drain(&mut self) -> Vec<(u8, Node)> {
+        let mut drained = Vec::with_capacity(self.len());
+        match self {
+            Children::Node4(n) => drained
+                .extend((0..n.len as usize).map(|i| (n.keys[i], n.children[i].take().unwrap()))),
+            Children::Node16(n) => drained
+                .extend((0..n.len as usize).map(|i| (n.keys[i], n.children[i].take().unwrap()))),
+            Children::Node48(n) => {
+                for byte in 0..=255u8 {
+                    if let Some(slot) = n.index[byte as usize].checked_sub(1) {
+                        drained.push((byte, n.children[slot as usize].take().unwrap()));
+                    }
+                }
+            }
+            Children::Node256(n) => {
+                for byte in 0..=255u8 {
+                    if let Some(node) = n.children[byte as usize].take() {
+                        drained.push((byte, node));
+                    }
+                }
+            }
+        }
+        drained
+    }
+
+    // Moves the children into a node of another size
+    fn resize(&mut self, into: Children) {
+        let mut old = std::mem::replace(self, into);
+        for (byte, node) in old.drain() {
+            self.add(byte, node);
+        }
+    }
+
+    fn add(&mut self, byte: u8, node: Node) {
+        match self {
+            Children::Node4(n) if n.len == 4 => self.resize(Children::Node16(Sorted::new())),
+            Children::Node16(n) if n.len == 16 => self.resize(Children::Node48(Node48::new())),
+            Children::Node48(n) if n.len == 48 => self.resize(Children::Node256(Node256::new())),
+            _ => {}
+        }
+        match self {
+            Children::Node4(n) => n.add(byte, node),
+            Children::Node16(n) => n.add(byte, node),
+            Children::Node48(n) => {
+                let slot = n.children.iter().position(|c| c.is_none()).unwrap();
+                n.children[slot] = Some(node);
+                n.index[byte as usize] = slot as u8 + 1;
+                n.len += 1;
+            }
+            Children::Node256(n) => {
+                n.children[byte as usize] = Some(node);
+                n.len += 1;
+            }
+        }
+    }
+
+    fn remove(&mut self, byte: u8) -> Option<Node> {
+        let node = match self {
+            Children::Node4(n) => n.remove(byte),
+            Children::Node16(n) => n.remove(byte),
+            Children::Node48(n) => {
+                let slot = n.index[byte as usize].checked_sub(1)?;
+                n.index[byte as usize] = 0;
+                n.len -= 1;
+                n.children[slot as usize].take()
+            }
+            Children::Node256(n) => {
+                let node = n.children[byte as usize].take()?;
+                n.len -= 1;
+                Some(node)
+            }
+        };
+        // Shrink with some slack so that a node does not flip between two sizes
+        match self {
+            Children::Node16(n) if n.len <= 3 => self.resize(Children::Node4(Sorted::new())),
+            Children::Node48(n) if n.len <= 12 => self.resize(Children::Node16(Sorted::new())),
+            Children::Node256(n) if n.len <= 37 => self.resize(Children::Node48(Node48::new())),
+            _ => {}
+        }
+        node
+    }
+
+    // Pushes the children whose byte is greater than `after` (all if None) onto the stack,
+    // largest first, so that they are popped in byte order. depth is the length of the
+    // path of this node.
+    fn push_children<'a>(&'a self, stack: &mut Vec<Frame<'a>>, after: Option<u8>, depth: usize) {
+        let include = |byte: u8| after.is_none_or(|after| byte > after);
+        let mut push = |byte: u8, node: &'a Option<Node>| {
+            if include(byte) {
+                stack.push(Frame {
+                    item: Item::Node(node.as_ref().unwrap()),
+                    depth,
+                    byte: Some(byte),
+                });
+            }
+        };
+        match self {
+            Children::Node4(n) => {
+                for i in (0..n.len as usize).rev() {
+                    push(n.keys[i], &n.children[i]);
+                }
+            }
+            Children::Node16(n) => {
+                for i in (0..n.len as usize).rev() {
+                    push(n.keys[i], &n.children[i]);
+                }
+            }
+            Children::Node48(n) => {
+                for byte in (0..=255u8).rev() {
+                    if let Some(slot) = n.index[byte as usize].checked_sub(1) {
+                        push(byte, &n.children[slot as usize]);
+                    }
+                }
+            }
+            Children::Node256(n) => {
+                for byte in (0..=255u8).rev() {
+                    if n.children[byte as usize].is_some() {
+                        push(byte, &n.children[byte as usize]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn new(prefix: &[u8]) -> Self {
+        Inner {
+            prefix: prefix.into(),
+            end: None,
+            children: Children::Node4(Sorted::new()),
+        }
+    }
+
+    // Places a leaf whose key is the rest of its key below the path of this node
+    fn place(&mut self, mut leaf: Box<Leaf>) {
+        match leaf.key.first() {
+            None => self.end = Some(leaf),
+            Some(&byte) => {
+                leaf.key = leaf.key[1..].into();
+                self.children.add(byte, Node::Leaf(leaf));
+            }
+        }
+    }
+}
+
+impl Node {
+    // Inserts at the node reached with the first `depth` bytes of the key
+    fn insert(&mut self, depth: usize, key: &[u8], entry: Entry) -> Option<Entry> {
+        let rest = &key[depth..];
+        match self {
+            Node::Leaf(old) if *old.key == *rest => Some(std::mem::replace(&mut old.entry, entry)),
+            Node::Leaf(old) => {
+                // Split into an inner node holding both leaves
+                let common = common_prefix_len(&old.key, rest);
+                let mut inner = Inner::new(&rest[..common]);
+                inner.place(Leaf::new(&rest[common..], entry));
+                let Node::Leaf(mut old) = std::mem::replace(self, Node::Inner(Box::new(inner)))
+                else {
+                    unreachable!()
+                };
+                let Node::Inner(inner) = self else {
+                    unreachable!()
+                };
+                old.key = old.key[common..].into();
+                inner.place(old);
+                None
+            }
+            Node::Inner(inner) => {
+                let common = common_prefix_len(&inner.prefix, rest);
+                if common < inner.prefix.len() {
+                    // The key leaves the compressed path: split the path
+                    let mut parent = Inner::new(&inner.prefix[..common]);
+                    let byte = inner.prefix[common];
+                    inner.prefix = inner.prefix[common + 1..].into();
+                    parent.place(Leaf::new(&rest[common..], entry));
+                    let old = std::mem::replace(self, Node::Inner(Box::new(parent)));
+                    let Node::Inner(parent) = self else {
+                        unreachable!()
+                    };
+                    parent.children.add(byte, old);
+                    return None;
+                }
+                let depth = depth + common;
+                match key.get(depth) {
+                    None => match &mut inner.end {
+                        Some(end) => Some(std::mem::replace(&mut end.entry, entry)),
+                        None => {
+                            inner.end = Some(Leaf::new(&[], entry));
+                            None
+                        }
+                    },
+                    Some(&byte) => match inner.children.find_mut(byte) {
+                        Some(child) => child.insert(depth + 1, key, entry),
+                        None => {
+                            let leaf = Leaf::new(&key[depth + 1..], entry);
+                            inner.children.add(byte, Node::Leaf(leaf));
+                            None
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    // Restores the invariant of an inner node that is left with a single key. The
+    // remaining key or node takes over the path of this node.
+    fn compact(&mut self) {
+        let Node::Inner(inner) = self else {
+            return;
+        };
+        if inner.children.len() + inner.end.is_some() as usize > 1 {
+            return;
+        }
+        if let Some(mut end) = inner.end.take() {
+            end.key = std::mem::take(&mut inner.prefix);
+            *self = Node::Leaf(end);
+            return;
+        }
+        let (byte, mut child) = inner.children.drain().pop().unwrap();
+        let mut path = std::mem::take(&mut inner.prefix).into_vec();
+        path.push(byte);
+        match &mut child {
+            Node::Leaf(leaf) => {
+                path.extend_from_slice(&leaf.key);
+                leaf.key = path.into();
+            }
+            Node::Inner(child) => {
+                path.extend_from_slice(&child.prefix);
+                child.prefix = path.into();
+            }
+        }
+        *self = child;
+    }
+}
+
+impl Art {
+    pub fn clear(&mut self) {
+        self.root = None;
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Entry> {
+        let mut node = self.root.as_ref()?;
+        let mut depth = 0;
+        loop {
+            match node {
+                Node::Leaf(leaf) => return (*leaf.key == key[depth..]).then_some(&leaf.entry),
+                Node::Inner(inner) => {
+                    if !key[depth..].starts_with(&inner.prefix) {
+                        return None;
+                    }
+                    depth += inner.prefix.len();
+                    match key.get(depth) {
+                        None => return inner.end.as_ref().map(|leaf| &leaf.entry),
+                        Some(&byte) => node = inner.children.find(byte)?,
+                    }
+                    depth += 1;
+                }
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        let mut node = self.root.as_mut()?;
+        let mut depth = 0;
+        loop {
+            match node {
+                Node::Leaf(leaf) => return (*leaf.key == key[depth..]).then_some(&mut leaf.entry),
+                Node::Inner(inner) => {
+                    if !key[depth..].starts_with(&inner.prefix) {
+                        return None;
+                    }
+                    depth += inner.prefix.len();
+                    match key.get(depth) {
+                        None => return inner.end.as_mut().map(|leaf| &mut leaf.entry),
+                        Some(&byte) => node = inner.children.find_mut(byte)?,
+                    }
+                    depth += 1;
+                }
+            }
+        }
+    }
+
+    /// Inserts or replaces the entry of the key. Returns the replaced entry.
+    pub fn insert(&mut self, key: Vec<u8>, entry: Entry) -> Option<Entry> {
+        match &mut self.root {
+            None => {
+                self.root = Some(Node::Leaf(Leaf::new(&key, entry)));
+                None
+            }
+            Some(root) => root.insert(0, &key, entry),
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let root = self.root.as_mut()?;
+        if let Node::Leaf(leaf) = root {
+            if *leaf.key != *key {
+                return None;
+            }
+            let Some(Node::Leaf(leaf)) = self.root.take() else {
+                unreachable!()
+            };
+            return Some(leaf.entry);
+        }
+        let entry = Self::remove_below(root, key, 0)?;
+        root.compact();
+        Some(entry)
+    }
+
+    // Removes the key from below the inner node reached with the first `depth` bytes
+    fn remove_below(node: &mut Node, key: &[u8], depth: usize) -> Option<Entry> {
+        let Node::Inner(inner) = node else {
+            unreachable!()
+        };
+        if !key[depth..].starts_with(&inner.prefix) {
+            return None;
+        }
+        let depth = depth + inner.prefix.len();
+        let Some(&byte) = key.get(depth) else {
+            return inner.end.take().map(|leaf| leaf.entry);
+        };
+        let child = inner.children.find_mut(byte)?;
+        match child {
+            Node::Leaf(leaf) if *leaf.key == key[depth + 1..] => {
+                match inner.children.remove(byte) {
+                    Some(Node::Leaf(leaf)) => Some(leaf.entry),
+                    _ => unreachable!(),
+                }
+            }
+            Node::Leaf(_) => None,
+            Node::Inner(_) => {
+                let entry = Self::remove_below(child, key, depth + 1)?;
+                child.compact();
+                Some(entry)
+            }
+        }
+    }
+
+    /// All the entries in key order.
+    pub fn iter(&self) -> Iter<'_> {
+        self.range_from(&[])
+    }
+
+    /// The entries whose key is greater than or equal to `start`, in key order.
+    pub fn range_from(&self, start: &[u8]) -> Iter<'_> {
+        let mut stack = Vec::new();
+        let mut node = self.root.as_ref();
+        // The path of the node is start[..depth], reached from the path of its parent,
+        // start[..frame_depth], with frame_byte
+        let (mut depth, mut frame_depth, mut frame_byte) = (0, 0, None);
+        while let Some(n) = node {
+            node = None;
+            let frame = Frame {
+                item: Item::Node(n),
+                depth: frame_depth,
+                byte: frame_byte,
+            };
+            match n {
+                Node::Leaf(leaf) => {
+                    if *leaf.key >= start[depth..] {
+                        stack.push(frame);
+                    }
+                }
+                Node::Inner(inner) => {
+                    let rest = &start[depth..];
+                    let len = inner.prefix.len().min(rest.len());
+                    match inner.prefix[..len].cmp(&rest[..len]) {
+                        Ordering::Less => {}
+                        Ordering::Greater => stack.push(frame),
+                        // start ends within the path: every key below is greater or equal
+                        Ordering::Equal if rest.len() <= inner.prefix.len() => stack.push(frame),
+                        Ordering::Equal => {
+                            // The end leaf is a proper prefix of start, so it is skipped
+                            depth += inner.prefix.len();
+                            let byte = start[depth];
+                            inner.children.push_children(&mut stack, Some(byte), depth);
+                            node = inner.children.find(byte);
+                            (frame_depth, frame_byte) = (depth, Some(byte));
+                            depth += 1;
+                        }
+                    }
+                }
+            }
+        }
+        // The frames only descend along start, so start holds their paths
+        Iter {
+            stack,
+            path: start.to_vec(),
+        }
+    }
+}
+
+enum Item<'a> {
+    Node(&'a Node),
+    Leaf(&'a Leaf),
+}
+
+// A node or leaf to visit, reached from the first `depth` bytes of the path with `byte`
+struct Frame<'a> {
+    item: Item<'a>,
+    depth: usize,
+    byte: Option<u8>,
+}
+
+/// Iterator over the entries of an `Art` in key order. The keys are rebuilt from the
+/// paths of the nodes.
+pub struct Iter<'a> {
+    stack: Vec<Frame<'a>>, // the next item on top
+    path: Vec<u8>,         // the path of the last visited node
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (Vec<u8>, &'a Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(frame) = self.stack.pop() {
+            self.path.truncate(frame.depth);
+            self.path.extend(frame.byte);
+            let leaf = match frame.item {
+                Item::Leaf(leaf) => leaf,
+                Item::Node(Node::Leaf(leaf)) => leaf,
+                Item::Node(Node::Inner(inner)) => {
+                    self.path.extend_from_slice(&inner.prefix);
+                    let depth = self.path.len();
+                    inner.children.push_children(&mut self.stack, None, depth);
+                    // A key ending at this node is a prefix of the keys below it
+                    if let Some(end) = &inner.end {
+                        self.stack.push(Frame {
+                            item: Item::Leaf(end),
+                            depth,
+                            byte: None,
+                        });
+                    }
+                    continue;
+                }
+            };
+            let mut key = Vec::with_capacity(self.path.len() + leaf.key.len());
+            key.extend_from_slice(&self.path);
+            key.extend_from_slice(&leaf.key);
+            return Some((key, &leaf.entry));
+        }
+        None
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn entry(val: &[u8]) -> Entry {
+        Entry::new(val.to_vec(), None)
+    }
+
+    fn value(entry: &Entry) -> Vec<u8> {
+        entry.value(None)
+    }
+
+    // Keys with long shared prefixes, keys that are prefixes of other keys and
+    // enough distinct bytes to grow nodes up to 256 children.
+    fn keys(seed: u64, count: usize) -> Vec<Vec<u8>> {
+        let mut x = seed;
+        let mut next = move || {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        (0..count)
+            .map(|_| {
+                let r = next();
+                let mut key = format!("tenant{}/table{}/", r % 3, (r >> 8) % 4).into_bytes();
+                let len = (r >> 16) % 4;
+                for i in 0..len {
+                    key.push((r >> (24 + 8 * i)) as u8);
+                }
+                key
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_matches_btree_map() {
+        let mut art = Art::default();
+        let mut expected = BTreeMap::new();
+        let keys = keys(0x2545F4914F6CDD1D, 5000);
+        for (i, key) in keys.iter().enumerate() {
+            let val = i.to_be_bytes();
+            let old = art.insert(key.clone(), entry(&val)).map(|e| value(&e));
+            assert_eq!(old, expected.insert(key.clone(), val.to_vec()));
+        }
+        for key in &keys {
+            assert_eq!(art.get(key).map(value), expected.get(key).cloned());
+        }
+        let all: Vec<_> = art.iter().map(|(k, e)| (k, value(e))).collect();
+        assert_eq!(all, expected.clone().into_iter().collect::<Vec<_>>());
+
+        // Remove every other key, which shrinks the nodes again
+        for key in keys.iter().step_by(2) {
+            let old = art.remove(key).map(|e| value(&e));
+            assert_eq!(old, expected.remove(key));
+        }
+        assert!(art.remove(b"tenant9/").is_none());
+        let all: Vec<_> = art.iter().map(|(k, e)| (k, value(e))).collect();
+        assert_eq!(all, expected.clone().into_iter().collect::<Vec<_>>());
+
+        for start in keys.iter().take(200).chain([&vec![], &b"tenant1".to_vec()]) {
+            let from: Vec<_> = art.range_from(start).map(|(k, _)| k).collect();
+            let want: Vec<_> = expected
+                .range(start.clone()..)
+                .map(|(k, _)| k.clone())
+                .collect();
+            assert_eq!(from, want);
+        }
+
+        for key in &keys {
+            art.remove(key);
+        }
+        assert!(art.iter().next().is_none());
+        assert!(art.root.is_none());
+    }
+
+    #[test]
+    fn test_prefix_keys() {
+        let mut art = Art::default();
+        for key in [&b"abc"[..], b"a", b"ab", b"", b"abd", b"b"] {
+            art.insert(key.to_vec(), entry(key));
+        }
+        let all: Vec<_> = art.iter().map(|(k, _)| k).collect();
+        assert_eq!(all, [&b""[..], b"a", b"ab", b"abc", b"abd", b"b"]);
+        assert_eq!(art.get(b"ab").map(value), Some(b"ab".to_vec()));
+        assert!(art.get(b"abcd").is_none());
+
+        art.get_mut(b"a").unwrap().val = Some(b"x".to_vec());
+        assert_eq!(art.get(b"a").map(value), Some(b"x".to_vec()));
+
+        assert!(art.remove(b"ab").is_some());
+        let from: Vec<_> = art.range_from(b"ab").map(|(k, _)| k).collect();
+        assert_eq!(from, [&b"abc"[..], b"abd", b"b"]);
+    }
+
+    // Bytes used by the nodes and the stored keys, without the values
+    fn memory(node: &Node) -> usize {
+        let leaf = |leaf: &Leaf| size_of::<Leaf>() + leaf.key.len();
+        match node {
+            Node::Leaf(l) => leaf(l),
+            Node::Inner(inner) => {
+                let children = match &inner.children {
+                    Children::Node4(_) => size_of::<Sorted<4>>(),
+                    Children::Node16(_) => size_of::<Sorted<16>>(),
+                    Children::Node48(_) => size_of::<Node48>(),
+                    Children::Node256(_) => size_of::<Node256>(),
+                };
+                let mut frames = Vec::new();
+                inner.children.push_children(&mut frames, None, 0);
+                let below: usize = frames
+                    .iter()
+                    .map(|frame| match frame.item {
+                        Item::Node(node) => memory(node),
+                        Item::Leaf(_) => unreachable!(),
+                    })
+                    .sum();
+                size_of::<Inner>()
+                    + inner.prefix.len()
+                    + children
+                    + inner.end.as_deref().map_or(0, leaf)
+                    + below
+            }
+        }
+    }
+
+    #[test]
+    fn test_memory_per_key() {
+        // Row keys sharing a long tenant/table path, as in a multi-tenant table
+        let prefix = b"tenant0042/orders_by_customer/2024-06/".to_vec();
+        let mut art = Art::default();
+        let count = 20_000u32;
+        for i in 0..count {
+            let mut key = prefix.clone();
+            key.extend_from_slice(&(i * 7).to_be_bytes());
+            art.insert(key, entry(b""));
+        }
+        let per_key = memory(art.root.as_ref().unwrap()) / count as usize;
+        // A BTreeMap stores each full key in its own Vec next to the entry, without
+        // counting its nodes
+        let key_len = prefix.len() + 4;
+        let btree_per_key = size_of::<Vec<u8>>() + key_len + size_of::<Entry>();
+        assert!(
+            per_key < btree_per_key,
+            "{} bytes per key, a BTreeMap needs more than {}",
+            per_key,
+            btree_per_key
+        );
+        // Small nodes are allocated at their size
+        assert!(size_of::<Inner>() <= 48);
+        assert!(size_of::<Sorted<4>>() <= 80);
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::VecDeque,
+    iter::FusedIterator,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::prelude::*;
+
+// Number of the latest changes kept for subscribers that fall behind or resume.
+const CHANGE_LOG_CAPACITY: usize = 1 << 16;
+
+// A change before it is committed: container, key, old and new value.
+pub type PendingChange = (ContainerId, Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>);
+
+struct LogState {
+    changes: VecDeque<Change>,
+    next: ChangePosition, // position of the next change
+}
+
+impl LogState {
+    fn first(&self) -> ChangePosition {
+        self.next - self.changes.len() as ChangePosition
+    }
+}
+
+/// The latest committed changes of the containers that capture their changes, in commit
+/// order. Each change has a position, and the changes of a transaction have consecutive
+/// positions.
+pub struct ChangeLog {
+    state: Mutex<LogState>,
+    appended: Condvar,
+}
+
+impl Default for ChangeLog {
+    fn default() -> Self {
+        ChangeLog {
+            state: Mutex::new(LogState {
+                changes: VecDeque::new(),
+                next: 0,
+            }),
+            appended: Condvar::new(),
+        }
+    }
+}
+
+impl ChangeLog {
+    // Commits a transaction with commit, which returns its changes, and appends them.
+    // Commits run one at a time so that the changes are appended in commit order.
+    pub fn commit(
+        &self,
+        txn_id: TxnId,
+        commit: impl FnOnce() -> Result<Vec<PendingChange>, StorageError>,
+    ) -> Result<(), StorageError> {
+        let mut state = self.state.lock().unwrap();
+        let changes = commit()?;
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let commit_time = SystemTime::now();
+        for (c_id, key, old, new) in changes {
+            let change = Change::new(state.next, c_id, key, old, new, commit_time, txn_id);
+            state.changes.push_back(change);
+            state.next += 1;
+        }
+        while state.changes.len() > CHANGE_LOG_CAPACITY {
+            state.changes.pop_front();
+        }
+        self.appended.notify_all();
+        Ok(())
+    }
+
+    pub fn end(&self) -> ChangePosition {
+        self.state.lock().unwrap().next
+    }
+
+    // The change at the position, waiting until the deadline for it to be committed.
+    fn get(&self, position: ChangePosition, deadline: Option<Instant>) -> ChangeResult {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if position < state.first() {
+                return Err(not_retained(state.first()));
+            }
+            if let Some(change) = state.changes.get((position - state.first()) as usize) {
+                return Ok(Some(change.clone()));
+            }
+            match deadline {
+                None => state = self.appended.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    state = self.appended.wait_timeout(state, deadline - now).unwrap().0;
+                }
+            }
+        }
+    }
+
+    pub fn subscribe(self: &Arc<Self>, from: ChangePosition) -> Result<ChangeStream, StorageError> {
+        let state = self.state.lock().unwrap();
+        if from < state.first() {
+            return Err(not_retained(state.first()));
+        }
+        Ok(ChangeStream {
+            log: Arc::clone(self),
+            next: from,
+            failed: false,
+        })
+    }
+}
+
+type ChangeResult = Result<Option<Change>, StorageError>;
+
+fn not_retained(first: ChangePosition) -> StorageError {
+    StorageError::new(Status::Error).with_source(format!(
+        "the changes before position {} are no longer retained",
+        first
+    ))
+}
+
+/// Committed changes from a position on, in commit order. Iterating blocks until the
+/// next change is committed. A subscriber that falls more than 65536 changes behind
+/// gets an error and has to catch up by other means, e.g. by a scan. The iterator ends
+/// after returning the error.
+pub struct ChangeStream {
+    log: Arc<ChangeLog>,
+    next: ChangePosition,
+    failed: bool, // the iterator returned an error and is done
+}
+
+impl ChangeStream {
+    /// Position of the next change of the stream. Subscribing from it later resumes the
+    /// stream where it is now.
+    pub fn position(&self) -> ChangePosition {
+        self.next
+    }
+
+    /// The next change if it is already committed.
+    pub fn try_next(&mut self) -> ChangeResult {
+        self.next_until(Some(Instant::now()))
+    }
+
+    /// The next change, waiting up to `timeout` for it to be committed.
+    pub fn next_timeout(&mut self, timeout: Duration) -> ChangeResult {
+        self.next_until(Some(Instant::now() + timeout))
+    }
+
+    fn next_until(&mut self, deadline: Option<Instant>) -> ChangeResult {
+        let change = self.log.get(self.next, deadline)?;
+        if change.is_some() {
+            self.next += 1;
+        }
+        Ok(change)
+    }
+}
+
+impl Iterator for ChangeStream {
+    type Item = Result<Change, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.next_until(None).transpose();
+        self.failed = matches!(result, Some(Err(_)));
+        result
+    }
+}
+
+impl FusedIterator for ChangeStream {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_old_changes_are_released() {
+        let log = Arc::new(ChangeLog::default());
+        let mut stream = log.subscribe(0).unwrap();
+        let changes = (0..=CHANGE_LOG_CAPACITY as u32)
+            .map(|i| (0, i.to_be_bytes().to_vec(), None, Some(vec![])))
+            .collect();
+        log.commit(1, || Ok(changes)).unwrap();
+        assert_eq!(log.end(), CHANGE_LOG_CAPACITY as ChangePosition + 1);
+        assert_eq!(stream.try_next().unwrap_err(), Status::Error);
+        // The iterator ends after the error
+        assert_eq!(stream.next().unwrap().unwrap_err(), Status::Error);
+        assert!(stream.next().is_none());
+        assert!(log.subscribe(0).is_err());
+        let mut stream = log.subscribe(1).unwrap();
+        assert_eq!(
+            stream.try_next().unwrap().unwrap().key(),
+            1u32.to_be_bytes()
+        );
+    }
+}
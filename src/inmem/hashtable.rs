@@ -0,0 +1,414 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeMap},
+    hash::BuildHasher,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::Instant,
+};
+
+use crossbeam_epoch::{self as epoch, Atomic, Guard, Owned};
+
+use super::{ttl::ExpiryQueue, AfterImage, Entries, Entry, EntryStore};
+use crate::prelude::*;
+
+// Number of buckets of a new table. Always a power of two.
+const INITIAL_BUCKETS: usize = 16;
+// Keys per bucket above which the table doubles.
+const LOAD_FACTOR: usize = 2;
+
+/// Hash container without a container-wide latch. The keys are spread over buckets that
+/// each have their own mutex, so writers to different buckets do not block each other
+/// and scans, which copy one bucket at a time, do not block writers.
+///
+/// The table doubles when it holds more than LOAD_FACTOR keys per bucket. The resizer
+/// links the new table to the current one and moves the buckets one by one, marking
+/// each bucket it has moved. An operation locks the bucket of its key and follows the
+/// link while the bucket is marked, so it always finds the bucket that holds the key,
+/// even during a resize. The old table is freed once no thread can still be reading it.
+pub struct HashTable {
+    table: Atomic<Table>,
+    len: AtomicUsize, // number of keys, the expired ones included
+    // Held by the resizer while it moves the buckets, and by the operations that visit
+    // all the buckets so that they see each key once.
+    resizing: Mutex<()>,
+    hasher: RandomState,
+    expiries: Mutex<ExpiryQueue>,
+    merge_operator: Option<MergeOperator>,
+}
+
+struct Table {
+    buckets: Box<[Mutex<Bucket>]>,
+    next: Atomic<Table>, // the table the buckets are moved to, once a resize began
+}
+
+#[derive(Default)]
+struct Bucket {
+    entries: Vec<(u64, Vec<u8>, Entry)>, // hash, key and entry
+    moved: bool,                         // the entries are in the next table
+}
+
+impl Table {
+    fn new(buckets: usize) -> Self {
+        Table {
+            buckets: (0..buckets).map(|_| Mutex::default()).collect(),
+            next: Atomic::null(),
+        }
+    }
+
+    fn bucket(&self, hash: u64) -> &Mutex<Bucket> {
+        &self.buckets[hash as usize & (self.buckets.len() - 1)]
+    }
+}
+
+impl Bucket {
+    fn position(&self, hash: u64, key: &[u8]) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|(h, k, _)| *h == hash && k.as_slice() == key)
+    }
+}
+
+impl HashTable {
+    pub fn new(merge_operator: Option<MergeOperator>) -> Self {
+        HashTable {
+            table: Atomic::new(Table::new(INITIAL_BUCKETS)),
+            len: AtomicUsize::new(0),
+            resizing: Mutex::new(()),
+            hasher: RandomState::new(),
+            expiries: Mutex::new(ExpiryQueue::default()),
+            merge_operator,
+        }
+    }
+
+    fn current<'g>(&self, guard: &'g Guard) -> &'g Table {
+        // Safety: the current table is only retired after it is replaced, and the guard
+        // keeps a retired table alive until it is dropped.
+        unsafe { self.table.load(Ordering::Acquire, guard).deref() }
+    }
+
+    // Locks the bucket that holds the keys of the hash.
+    fn lock<'g>(&self, hash: u64, guard: &'g Guard) -> MutexGuard<'g, Bucket> {
+        let mut table = self.current(guard);
+        loop {
+            let bucket = table.bucket(hash).lock().unwrap();
+            if !bucket.moved {
+                return bucket;
+            }
+            drop(bucket);
+            // Safety: the next table is linked before any bucket is marked as moved, and
+            // is retired after the table that links to it.
+            table = unsafe { table.next.load(Ordering::Acquire, guard).deref() };
+        }
+    }
+
+    fn schedule_expiry(&self, key: &[u8], expire_at: Option<Instant>) {
+        if let Some(expire_at) = expire_at {
+            self.expiries.lock().unwrap().push(expire_at, key.to_vec());
+        }
+    }
+
+    // Doubles the table if it holds too many keys, unless another thread is resizing it.
+    fn grow_if_needed(&self, guard: &Guard) {
+        let buckets = self.current(guard).buckets.len();
+        if self.len.load(Ordering::Relaxed) <= buckets * LOAD_FACTOR {
+            return;
+        }
+        let Ok(_resizing) = self.resizing.try_lock() else {
+            return;
+        };
+        let current = self.table.load(Ordering::Acquire, guard);
+        // Safety: see current()
+        let table = unsafe { current.deref() };
+        if self.len.load(Ordering::Relaxed) <= table.buckets.len() * LOAD_FACTOR {
+            return; // grown by another thread meanwhile
+        }
+        let next = Owned::new(Table::new(table.buckets.len() * 2)).into_shared(guard);
+        table.next.store(next, Ordering::Release);
+        // Safety: the next table is only retired by a later resize, which waits for this one
+        let next_table = unsafe { next.deref() };
+        for bucket in table.buckets.iter() {
+            let mut bucket = bucket.lock().unwrap();
+            for (hash, key, entry) in bucket.entries.drain(..) {
+                let mut target = next_table.bucket(hash).lock().unwrap();
+                target.entries.push((hash, key, entry));
+            }
+            bucket.moved = true;
+        }
+        self.table.store(next, Ordering::Release);
+        // Safety: the table is no longer reachable from self.table, and the threads that
+        // loaded it before are pinned.
+        unsafe { guard.defer_destroy(current) };
+    }
+
+    // Calls f with each bucket of the current table. No bucket is moved meanwhile.
+    fn for_each_bucket(&self, mut f: impl FnMut(&mut Bucket)) {
+        let _resizing = self.resizing.lock().unwrap();
+        let guard = epoch::pin();
+        for bucket in self.current(&guard).buckets.iter() {
+            f(&mut bucket.lock().unwrap());
+        }
+    }
+
+    /// Number of buckets of the table. Scans use it as their stride.
+    pub fn buckets(&self) -> usize {
+        self.current(&epoch::pin()).buckets.len()
+    }
+
+    /// Copies the non-expired entries of bucket `index` of a table of `stride` buckets.
+    /// The table only grows by doubling, so these entries are now in the buckets
+    /// `index + k * stride` and a scan that began with `stride` buckets sees each key once.
+    pub fn bucket_entries(&self, stride: usize, index: usize) -> Entries {
+        let _resizing = self.resizing.lock().unwrap();
+        let guard = epoch::pin();
+        let table = self.current(&guard);
+        let mut entries = Vec::new();
+        for bucket in table.buckets.iter().skip(index).step_by(stride) {
+            let bucket = bucket.lock().unwrap();
+            for (_, key, entry) in bucket.entries.iter().filter(|(.., e)| !e.is_expired()) {
+                entries.push((key.clone(), entry.value(self.merge_operator.as_ref())));
+            }
+        }
+        entries
+    }
+}
+
+impl Drop for HashTable {
+    fn drop(&mut self) {
+        // Safety: no other thread can access the table anymore. The retired tables are
+        // freed by the epoch collector.
+        unsafe {
+            drop(
+                self.table
+                    .load(Ordering::Relaxed, epoch::unprotected())
+                    .into_owned(),
+            )
+        }
+    }
+}
+
+impl EntryStore for HashTable {
+    fn clear_entries(&self) {
+        self.for_each_bucket(|bucket| {
+            self.len.fetch_sub(bucket.entries.len(), Ordering::Relaxed);
+            bucket.entries.clear();
+        });
+        self.expiries.lock().unwrap().clear();
+    }
+
+    fn insert_entry(
+        &self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        expire_at: Option<Instant>,
+    ) -> Result<(), Status> {
+        let hash = self.hasher.hash_one(&key);
+        let guard = epoch::pin();
+        let mut bucket = self.lock(hash, &guard);
+        match bucket.position(hash, &key) {
+            Some(i) if !bucket.entries[i].2.is_expired() => return Err(Status::KeyExists),
+            Some(i) => bucket.entries[i].2 = Entry::new(val, expire_at),
+            None => {
+                bucket
+                    .entries
+                    .push((hash, key.clone(), Entry::new(val, expire_at)));
+                self.len.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        drop(bucket);
+        self.schedule_expiry(&key, expire_at);
+        self.grow_if_needed(&guard);
+        Ok(())
+    }
+
+    fn get_at(&self, key: &[u8], at: Instant) -> Result<(Vec<u8>, Option<Instant>), Status> {
+        let hash = self.hasher.hash_one(key);
+        let guard = epoch::pin();
+        let bucket = self.lock(hash, &guard);
+        match bucket.position(hash, key).map(|i| &bucket.entries[i].2) {
+            Some(entry) if !entry.is_expired_at(at) => {
+                Ok((entry.value(self.merge_operator.as_ref()), entry.expire_at))
+            }
+            _ => Err(Status::KeyNotFound),
+        }
+    }
+
+    fn update_entry(
+        &self,
+        key: &[u8],
+        val: Vec<u8>,
+        expire_at: Option<Instant>,
+    ) -> Result<Entry, Status> {
+        let hash = self.hasher.hash_one(key);
+        let guard = epoch::pin();
+        let mut bucket = self.lock(hash, &guard);
+        let old = match bucket.position(hash, key) {
+            Some(i) if !bucket.entries[i].2.is_expired() => {
+                std::mem::replace(&mut bucket.entries[i].2, Entry::new(val, expire_at))
+            }
+            _ => return Err(Status::KeyNotFound),
+        };
+        drop(bucket);
+        self.schedule_expiry(key, expire_at);
+        Ok(old)
+    }
+
+    fn merge_entry(
+        &self,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+        image: bool,
+    ) -> Result<AfterImage, Status> {
+        let merge_operator = self.merge_operator.as_ref().ok_or(Status::Error)?;
+        let hash = self.hasher.hash_one(&key);
+        let guard = epoch::pin();
+        let mut bucket = self.lock(hash, &guard);
+        let i = match bucket.position(hash, &key) {
+            Some(i) if !bucket.entries[i].2.is_expired() => {
+                bucket.entries[i].2.merge(operand, merge_operator);
+                i
+            }
+            Some(i) => {
+                bucket.entries[i].2 = Entry::operand(operand);
+                i
+            }
+            None => {
+                bucket.entries.push((hash, key, Entry::operand(operand)));
+                self.len.fetch_add(1, Ordering::Relaxed);
+                bucket.entries.len() - 1
+            }
+        };
+        let entry = &bucket.entries[i].2;
+        let after = image.then(|| (entry.value(Some(merge_operator)), entry.expire_at));
+        drop(bucket);
+        self.grow_if_needed(&guard);
+        Ok(after)
+    }
+
+    fn remove_entry(&self, key: &[u8]) -> Result<Entry, Status> {
+        let hash = self.hasher.hash_one(key);
+        let guard = epoch::pin();
+        let mut bucket = self.lock(hash, &guard);
+        match bucket.position(hash, key) {
+            Some(i) if !bucket.entries[i].2.is_expired() => {
+                let (.., entry) = bucket.entries.swap_remove(i);
+                self.len.fetch_sub(1, Ordering::Relaxed);
+                Ok(entry)
+            }
+            _ => Err(Status::KeyNotFound),
+        }
+    }
+
+    fn remove_range_entries(
+        &self,
+        _start: &[u8],
+        _end: &[u8],
+    ) -> Result<BTreeMap<Vec<u8>, Entry>, Status> {
+        Err(Status::Error) // Hash containers are not ordered
+    }
+
+    fn prefix_values(&self, _prefix: &[u8]) -> Result<Vec<Vec<u8>>, Status> {
+        Err(Status::Error) // Hash containers are not ordered
+    }
+
+    fn entries_from(
+        &self,
+        _start: Bound<&[u8]>,
+        _limit: usize,
+        _at: Instant,
+    ) -> Result<Entries, Status> {
+        Err(Status::Error) // Hash containers are not ordered
+    }
+
+    fn for_each_entry(&self, at: Instant, f: &mut dyn FnMut(&[u8], &Entry)) {
+        self.for_each_bucket(|bucket| {
+            for (_, key, entry) in bucket.entries.iter().filter(|(.., e)| !e.is_expired_at(at)) {
+                f(key, entry);
+            }
+        });
+    }
+
+    fn remove_expired(&self, before: Instant) {
+        let mut keys = Vec::new();
+        {
+            let mut expiries = self.expiries.lock().unwrap();
+            while let Some(key) = expiries.pop_expired(before) {
+                keys.push(key);
+            }
+        }
+        let guard = epoch::pin();
+        for key in keys {
+            let hash = self.hasher.hash_one(&key);
+            let mut bucket = self.lock(hash, &guard);
+            // The key might have been overwritten with a later expiry or no expiry
+            if let Some(i) = bucket.position(hash, &key) {
+                if bucket.entries[i].2.is_expired_at(before) {
+                    bucket.entries.swap_remove(i);
+                    self.len.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{collections::HashSet, thread};
+
+    use super::*;
+
+    fn key(i: usize) -> Vec<u8> {
+        i.to_be_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_writers_during_resizes() {
+        let table = HashTable::new(None);
+        thread::scope(|s| {
+            for t in 0..4 {
+                let table = &table;
+                s.spawn(move || {
+                    for i in (t..4000).step_by(4) {
+                        table.insert_entry(key(i), key(i), None).unwrap();
+                        assert_eq!(table.get_with_expiry(&key(i)).unwrap().0, key(i));
+                        if i % 3 == 0 {
+                            table.remove_entry(&key(i)).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        assert!(table.buckets() > INITIAL_BUCKETS);
+        for i in 0..4000 {
+            assert_eq!(table.get_with_expiry(&key(i)).is_ok(), i % 3 != 0);
+        }
+        assert_eq!(
+            table.len.load(Ordering::Relaxed),
+            4000 - 4000usize.div_ceil(3)
+        );
+    }
+
+    #[test]
+    fn test_scan_across_resizes() {
+        let table = HashTable::new(None);
+        for i in 0..100 {
+            table.insert_entry(key(i), vec![], None).unwrap();
+        }
+        let stride = table.buckets();
+        let mut keys: Vec<_> = table.bucket_entries(stride, 0).into_iter().collect();
+        // The table grows several times between the buckets of the scan
+        for i in 100..2000 {
+            table.insert_entry(key(i), vec![], None).unwrap();
+        }
+        assert!(table.buckets() > stride);
+        for index in 1..stride {
+            keys.extend(table.bucket_entries(stride, index));
+        }
+        let unique: HashSet<_> = keys.iter().map(|(k, _)| k.clone()).collect();
+        assert_eq!(unique.len(), keys.len(), "a key is returned twice");
+        // The keys present during the whole scan are all returned
+        assert!((0..100).all(|i| unique.contains(&key(i))));
+    }
+}
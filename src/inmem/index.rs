@@ -0,0 +1,58 @@
+use std::{sync::Arc, time::Instant};
+
+use super::Storage;
+use crate::{keys::encode_bytes, prelude::*};
+
+/// Secondary index of a container. The index is stored in a BTree container
+/// whose keys are the encoded secondary key followed by the primary key, and
+/// whose values are the primary keys. Because the primary key is a part of the
+/// index key, multiple primary keys can share the same secondary key.
+pub struct SecondaryIndex {
+    storage: Arc<Storage>,
+    key_extractor: KeyExtractor,
+}
+
+impl SecondaryIndex {
+    pub fn new(storage: Arc<Storage>, key_extractor: KeyExtractor) -> Self {
+        SecondaryIndex {
+            storage,
+            key_extractor,
+        }
+    }
+
+    // The index entry expires together with the indexed key.
+    pub fn insert(&self, key: &[u8], val: &[u8], expire_at: Option<Instant>) {
+        if let Some(sec_key) = (self.key_extractor)(key, val) {
+            // Index keys are unique because they contain the primary key. An expired
+            // index entry with the same index key is overwritten.
+            let _ = self
+                .storage
+                .insert(index_key(&sec_key, key), key.to_vec(), expire_at);
+        }
+    }
+
+    pub fn remove(&self, key: &[u8], val: &[u8]) {
+        if let Some(sec_key) = (self.key_extractor)(key, val) {
+            let _ = self.storage.remove(&index_key(&sec_key, key));
+        }
+    }
+
+    pub fn clear(&self) {
+        self.storage.clear(false);
+    }
+}
+
+/// Encode the secondary key so that no encoded key is a prefix of another and
+/// the byte order of the secondary keys is preserved. Same encoding as the
+/// byte string keys of typed containers.
+pub fn encode_secondary_key(sec_key: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(sec_key.len() + 2);
+    encode_bytes(sec_key, &mut encoded);
+    encoded
+}
+
+fn index_key(sec_key: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut index_key = encode_secondary_key(sec_key);
+    index_key.extend_from_slice(key);
+    index_key
+}
@@ -1,233 +1,1410 @@
 use std::{
     cell::UnsafeCell,
-    collections::{BTreeMap, HashMap, HashSet},
-    sync::{Arc, Mutex, RwLock, RwLockReadGuard},
+    collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet},
+    hash::BuildHasher,
+    ops::{Bound, Range},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock, Weak,
+    },
+    time::{Duration, Instant},
 };
 
-use crate::{prelude::*, rwlatch::RwLatch};
+// The locks of the containers and the transactions, which guard the version logs, the
+// indexes, the undo logs and the transaction tables. Under `--cfg loom` they are loom's
+// so that the model checker also explores their interleavings (see loom_tests).
+#[cfg(loom)]
+use loom::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(not(loom))]
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-enum Storage {
-    HashMap(RwLatch, UnsafeCell<HashMap<Vec<u8>, Vec<u8>>>),
-    BTreeMap(RwLatch, UnsafeCell<BTreeMap<Vec<u8>, Vec<u8>>>),
+mod art;
+mod changes;
+mod hashtable;
+mod index;
+mod skiplist;
+mod ttl;
+mod undo;
+mod version;
+mod watch;
+
+use crate::{
+    prelude::*,
+    rwlatch::{ExclusiveGuard, LatchMode, RwLatch, SharedGuard},
+};
+use art::Art;
+pub use changes::ChangeStream;
+use changes::{ChangeLog, PendingChange};
+use hashtable::HashTable;
+use index::{encode_secondary_key, SecondaryIndex};
+use skiplist::SkipList;
+use ttl::{ExpiryQueue, Sweeper};
+use undo::{AfterImage, BeforeImage, UndoLog, UndoRecord};
+use version::{ReadPoint, VersionLog};
+use watch::Watchers;
+pub use watch::{Watch, WatchEvent, WatchTarget};
+
+// Number of merge operands kept per key before they are folded into the value.
+const MAX_MERGE_OPERANDS: usize = 16;
+
+// Keys and values copied out of a container.
+type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+// Number of entries a snapshot scan copies at a time from an ordered container.
+const SNAPSHOT_SCAN_BATCH: usize = 256;
+
+// Number of stripes of the version log of a container that is not split into shards.
+const VERSION_STRIPES: usize = 16;
+
+pub struct Entry {
+    val: Option<Vec<u8>>,       // None if the key only has merge operands
+    operands: Vec<Vec<u8>>,     // merge operands not yet applied to val
+    expire_at: Option<Instant>, // None if the key never expires
 }
 
-unsafe impl Sync for Storage {}
+impl Entry {
+    fn new(val: Vec<u8>, expire_at: Option<Instant>) -> Self {
+        Entry {
+            val: Some(val),
+            operands: Vec::new(),
+            expire_at,
+        }
+    }
 
-impl Storage {
-    fn new(c_type: ContainerType) -> Self {
-        match c_type {
-            ContainerType::Hash => {
-                Storage::HashMap(RwLatch::default(), UnsafeCell::new(HashMap::new()))
-            }
-            ContainerType::BTree => {
-                Storage::BTreeMap(RwLatch::default(), UnsafeCell::new(BTreeMap::new()))
-            }
+    fn operand(operand: Vec<u8>) -> Self {
+        Entry {
+            val: None,
+            operands: vec![operand],
+            expire_at: None,
         }
     }
 
-    fn shared(&self) {
-        match self {
-            Storage::HashMap(latch, _) => latch.shared(),
-            Storage::BTreeMap(latch, _) => latch.shared(),
+    fn is_expired(&self) -> bool {
+        self.is_expired_at(Instant::now())
+    }
+
+    fn is_expired_at(&self, at: Instant) -> bool {
+        self.expire_at.is_some_and(|t| t <= at)
+    }
+
+    // Value with all the pending operands applied
+    fn value(&self, merge_operator: Option<&MergeOperator>) -> Vec<u8> {
+        match (self.operands.is_empty(), merge_operator) {
+            (true, _) => self.val.clone().unwrap(),
+            (false, Some(merge)) => merge(self.val.as_deref(), &self.operands),
+            (false, None) => unreachable!("merge operands without a merge operator"),
         }
     }
 
-    fn exclusive(&self) {
-        match self {
-            Storage::HashMap(latch, _) => latch.exclusive(),
-            Storage::BTreeMap(latch, _) => latch.exclusive(),
+    fn merge(&mut self, operand: Vec<u8>, merge_operator: &MergeOperator) {
+        self.operands.push(operand);
+        if self.operands.len() >= MAX_MERGE_OPERANDS {
+            self.val = Some(merge_operator(self.val.as_deref(), &self.operands));
+            self.operands.clear();
         }
     }
+}
 
-    fn release_shared(&self) {
-        match self {
-            Storage::HashMap(latch, _) => latch.release_shared(),
-            Storage::BTreeMap(latch, _) => latch.release_shared(),
+// The cell of the data of a shard. Under `--cfg loom` it is loom's UnsafeCell, which
+// checks that each access is ordered by the latch after the conflicting ones. The
+// access is checked when the reference is taken.
+#[cfg(loom)]
+use loom::cell::UnsafeCell as ShardCell;
+
+#[cfg(not(loom))]
+pub struct ShardCell<T>(UnsafeCell<T>);
+
+#[cfg(not(loom))]
+impl<T> ShardCell<T> {
+    fn new(value: T) -> Self {
+        ShardCell(UnsafeCell::new(value))
+    }
+
+    fn with<R>(&self, f: impl FnOnce(*const T) -> R) -> R {
+        f(self.0.get())
+    }
+
+    fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+        f(self.0.get())
+    }
+}
+
+pub enum Map {
+    Hash(ShardCell<HashMap<Vec<u8>, Entry>>),
+    BTree(ShardCell<BTreeMap<Vec<u8>, Entry>>),
+    Art(ShardCell<Art>),
+}
+
+enum MapRef<'a> {
+    Hash(&'a HashMap<Vec<u8>, Entry>),
+    BTree(&'a BTreeMap<Vec<u8>, Entry>),
+    Art(&'a Art),
+}
+
+enum MapMut<'a> {
+    Hash(&'a mut HashMap<Vec<u8>, Entry>),
+    BTree(&'a mut BTreeMap<Vec<u8>, Entry>),
+    Art(&'a mut Art),
+}
+
+// Entry-level operations of a part of a container. Implemented by the latched shards of
+// Hash, BTree and Art containers and by the skiplist of SkipList containers.
+trait EntryStore: Sync {
+    fn clear_entries(&self);
+    // An expired key is treated as absent, so inserting over it succeeds.
+    fn insert_entry(
+        &self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        expire_at: Option<Instant>,
+    ) -> Result<(), Status>;
+    // Returns the value and the expiry of the key if it had not expired at `at`. Reads of
+    // the current entries pass the current time; reads at a past time see the keys that
+    // have expired since.
+    fn get_at(&self, key: &[u8], at: Instant) -> Result<(Vec<u8>, Option<Instant>), Status>;
+    fn get_with_expiry(&self, key: &[u8]) -> Result<(Vec<u8>, Option<Instant>), Status> {
+        self.get_at(key, Instant::now())
+    }
+    // Replaces the value and its expiry and returns the old entry. Updating without an
+    // expiry makes the key persistent.
+    fn update_entry(
+        &self,
+        key: &[u8],
+        val: Vec<u8>,
+        expire_at: Option<Instant>,
+    ) -> Result<Entry, Status>;
+    // Records the operand. It is applied to the value when the value is read or when
+    // too many operands are accumulated. A missing or expired key is merged into nothing.
+    // Returns the value and the expiry after the merge if `image` is set, which applies
+    // the operands, and None otherwise.
+    fn merge_entry(
+        &self,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+        image: bool,
+    ) -> Result<AfterImage, Status>;
+    // Removes the key and returns its entry. An expired key is left in place for
+    // remove_expired, which keeps it as long as reads at a past time may see it.
+    fn remove_entry(&self, key: &[u8]) -> Result<Entry, Status>;
+    // Removes the keys in [start, end) and returns the removed entries.
+    fn remove_range_entries(
+        &self,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<BTreeMap<Vec<u8>, Entry>, Status>;
+    // Returns the values of the non-expired keys that start with prefix, in key order.
+    fn prefix_values(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Status>;
+    // Copies up to limit entries not expired at `at` from start, in key order.
+    fn entries_from(
+        &self,
+        start: Bound<&[u8]>,
+        limit: usize,
+        at: Instant,
+    ) -> Result<Entries, Status>;
+    // Calls f with every entry not expired at `at`.
+    fn for_each_entry(&self, at: Instant, f: &mut dyn FnMut(&[u8], &Entry));
+    // Copies the entries of the keys that are present and were not expired at `at`.
+    fn entries_of(&self, keys: &[Vec<u8>], at: Instant) -> Entries {
+        keys.iter()
+            .filter_map(|k| Some((k.clone(), self.get_at(k, at).ok()?.0)))
+            .collect()
+    }
+    // Physically removes the keys that expired at or before `before`.
+    fn remove_expired(&self, before: Instant);
+}
+
+// A part of a container with its own latch. Hash containers can be split into several
+// shards by key hash so that writers to different shards do not contend. Ordered
+// containers always have a single shard.
+pub struct Shard {
+    latch: RwLatch,
+    map: Map,
+    expiries: ShardCell<ExpiryQueue>,
+    merge_operator: Option<MergeOperator>,
+}
+
+unsafe impl Sync for Shard {}
+
+impl Shard {
+    fn new(map: Map, merge_operator: Option<MergeOperator>, latch_mode: LatchMode) -> Self {
+        Shard {
+            latch: RwLatch::new(latch_mode),
+            map,
+            expiries: ShardCell::new(ExpiryQueue::default()),
+            merge_operator,
         }
     }
 
-    fn release_exclusive(&self) {
-        match self {
-            Storage::HashMap(latch, _) => latch.release_exclusive(),
-            Storage::BTreeMap(latch, _) => latch.release_exclusive(),
+    // The map and the expiry queue are only accessed through read(), write() and expiries().
+    // Mutable access requires the exclusive guard of this shard's latch, so mutating
+    // under a shared latch does not compile. Debug builds additionally check that the latch
+    // is held (by the current thread, for writes).
+    fn read(&self) -> MapRef<'_> {
+        debug_assert!(
+            self.latch.is_locked(),
+            "container read without holding its latch"
+        );
+        match &self.map {
+            Map::Hash(h) => MapRef::Hash(h.with(|h| unsafe { &*h })),
+            Map::BTree(b) => MapRef::BTree(b.with(|b| unsafe { &*b })),
+            Map::Art(a) => MapRef::Art(a.with(|a| unsafe { &*a })),
         }
     }
 
-    fn clear(&self) {
-        self.exclusive();
-        match self {
-            Storage::HashMap(_, h) => {
-                let h = unsafe { &mut *h.get() };
+    #[allow(clippy::mut_from_ref)]
+    fn write<'a>(&'a self, guard: &'a ExclusiveGuard<'_>) -> MapMut<'a> {
+        guard.assert_holds(&self.latch);
+        match &self.map {
+            Map::Hash(h) => MapMut::Hash(h.with_mut(|h| unsafe { &mut *h })),
+            Map::BTree(b) => MapMut::BTree(b.with_mut(|b| unsafe { &mut *b })),
+            Map::Art(a) => MapMut::Art(a.with_mut(|a| unsafe { &mut *a })),
+        }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn expiries<'a>(&'a self, guard: &'a ExclusiveGuard<'_>) -> &'a mut ExpiryQueue {
+        guard.assert_holds(&self.latch);
+        self.expiries.with_mut(|e| unsafe { &mut *e })
+    }
+
+    fn schedule_expiry(&self, guard: &ExclusiveGuard<'_>, key: &[u8], expire_at: Option<Instant>) {
+        if let Some(expire_at) = expire_at {
+            self.expiries(guard).push(expire_at, key.to_vec());
+        }
+    }
+}
+
+impl EntryStore for Shard {
+    fn clear_entries(&self) {
+        let guard = self.latch.exclusive();
+        match self.write(&guard) {
+            MapMut::Hash(h) => {
                 h.clear();
             }
-            Storage::BTreeMap(_, b) => {
-                let b = unsafe { &mut *b.get() };
+            MapMut::BTree(b) => {
                 b.clear();
             }
+            MapMut::Art(a) => {
+                a.clear();
+            }
         }
-        self.release_exclusive();
+        self.expiries(&guard).clear();
     }
 
-    fn insert(&self, key: Vec<u8>, val: Vec<u8>) -> Result<(), Status> {
-        self.exclusive();
-        let result = match self {
-            Storage::HashMap(_, h) => {
-                let h = unsafe { &mut *h.get() };
-                match h.entry(key) {
-                    std::collections::hash_map::Entry::Occupied(_) => Err(Status::KeyExists),
-                    std::collections::hash_map::Entry::Vacant(entry) => {
-                        entry.insert(val);
-                        Ok(())
-                    }
+    fn insert_entry(
+        &self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        expire_at: Option<Instant>,
+    ) -> Result<(), Status> {
+        let guard = self.latch.exclusive();
+        match self.write(&guard) {
+            MapMut::Hash(h) => match h.entry(key) {
+                std::collections::hash_map::Entry::Occupied(mut entry)
+                    if entry.get().is_expired() =>
+                {
+                    self.schedule_expiry(&guard, entry.key(), expire_at);
+                    entry.insert(Entry::new(val, expire_at));
+                    Ok(())
                 }
-            }
-            Storage::BTreeMap(_, b) => {
-                let b = unsafe { &mut *b.get() };
-                match b.entry(key) {
-                    std::collections::btree_map::Entry::Occupied(_) => Err(Status::KeyExists),
-                    std::collections::btree_map::Entry::Vacant(entry) => {
-                        entry.insert(val);
-                        Ok(())
-                    }
+                std::collections::hash_map::Entry::Occupied(_) => Err(Status::KeyExists),
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    self.schedule_expiry(&guard, entry.key(), expire_at);
+                    entry.insert(Entry::new(val, expire_at));
+                    Ok(())
+                }
+            },
+            MapMut::BTree(b) => match b.entry(key) {
+                std::collections::btree_map::Entry::Occupied(mut entry)
+                    if entry.get().is_expired() =>
+                {
+                    self.schedule_expiry(&guard, entry.key(), expire_at);
+                    entry.insert(Entry::new(val, expire_at));
+                    Ok(())
+                }
+                std::collections::btree_map::Entry::Occupied(_) => Err(Status::KeyExists),
+                std::collections::btree_map::Entry::Vacant(entry) => {
+                    self.schedule_expiry(&guard, entry.key(), expire_at);
+                    entry.insert(Entry::new(val, expire_at));
+                    Ok(())
+                }
+            },
+            MapMut::Art(a) => {
+                if a.get(&key).is_some_and(|e| !e.is_expired()) {
+                    return Err(Status::KeyExists);
                 }
+                self.schedule_expiry(&guard, &key, expire_at);
+                a.insert(key, Entry::new(val, expire_at));
+                Ok(())
             }
+        }
+    }
+
+    fn get_at(&self, key: &[u8], at: Instant) -> Result<(Vec<u8>, Option<Instant>), Status> {
+        let _guard = self.latch.shared();
+        match self.read() {
+            MapRef::Hash(h) => match h.get(key) {
+                Some(entry) if !entry.is_expired_at(at) => {
+                    Ok((entry.value(self.merge_operator.as_ref()), entry.expire_at))
+                }
+                _ => Err(Status::KeyNotFound),
+            },
+            MapRef::BTree(b) => match b.get(key) {
+                Some(entry) if !entry.is_expired_at(at) => {
+                    Ok((entry.value(self.merge_operator.as_ref()), entry.expire_at))
+                }
+                _ => Err(Status::KeyNotFound),
+            },
+            MapRef::Art(a) => match a.get(key) {
+                Some(entry) if !entry.is_expired_at(at) => {
+                    Ok((entry.value(self.merge_operator.as_ref()), entry.expire_at))
+                }
+                _ => Err(Status::KeyNotFound),
+            },
+        }
+    }
+
+    fn update_entry(
+        &self,
+        key: &[u8],
+        val: Vec<u8>,
+        expire_at: Option<Instant>,
+    ) -> Result<Entry, Status> {
+        let guard = self.latch.exclusive();
+        let result = match self.write(&guard) {
+            MapMut::Hash(h) => match h.get_mut(key) {
+                Some(entry) if !entry.is_expired() => {
+                    Ok(std::mem::replace(entry, Entry::new(val, expire_at)))
+                }
+                _ => Err(Status::KeyNotFound),
+            },
+            MapMut::BTree(b) => match b.get_mut(key) {
+                Some(entry) if !entry.is_expired() => {
+                    Ok(std::mem::replace(entry, Entry::new(val, expire_at)))
+                }
+                _ => Err(Status::KeyNotFound),
+            },
+            MapMut::Art(a) => match a.get_mut(key) {
+                Some(entry) if !entry.is_expired() => {
+                    Ok(std::mem::replace(entry, Entry::new(val, expire_at)))
+                }
+                _ => Err(Status::KeyNotFound),
+            },
         };
-        self.release_exclusive();
+        if result.is_ok() {
+            self.schedule_expiry(&guard, key, expire_at);
+        }
         result
     }
 
-    fn get(&self, key: &[u8]) -> Result<Vec<u8>, Status> {
-        self.shared();
-        let result = match self {
-            Storage::HashMap(_, h) => {
-                let h = unsafe { &*h.get() };
-                match h.get(key) {
-                    Some(val) => Ok(val.clone()),
-                    None => Err(Status::KeyNotFound),
+    fn merge_entry(
+        &self,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+        image: bool,
+    ) -> Result<AfterImage, Status> {
+        let merge_operator = self.merge_operator.as_ref().ok_or(Status::Error)?;
+        let guard = self.latch.exclusive();
+        let lookup = image.then(|| key.clone());
+        match self.write(&guard) {
+            MapMut::Hash(h) => match h.entry(key) {
+                std::collections::hash_map::Entry::Occupied(mut entry) => {
+                    if entry.get().is_expired() {
+                        entry.insert(Entry::operand(operand));
+                    } else {
+                        entry.get_mut().merge(operand, merge_operator);
+                    }
                 }
-            }
-            Storage::BTreeMap(_, b) => {
-                let b = unsafe { &*b.get() };
-                match b.get(key) {
-                    Some(val) => Ok(val.clone()),
-                    None => Err(Status::KeyNotFound),
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    entry.insert(Entry::operand(operand));
                 }
-            }
+            },
+            MapMut::BTree(b) => match b.entry(key) {
+                std::collections::btree_map::Entry::Occupied(mut entry) => {
+                    if entry.get().is_expired() {
+                        entry.insert(Entry::operand(operand));
+                    } else {
+                        entry.get_mut().merge(operand, merge_operator);
+                    }
+                }
+                std::collections::btree_map::Entry::Vacant(entry) => {
+                    entry.insert(Entry::operand(operand));
+                }
+            },
+            MapMut::Art(a) => match a.get_mut(&key) {
+                Some(entry) if !entry.is_expired() => entry.merge(operand, merge_operator),
+                _ => {
+                    a.insert(key, Entry::operand(operand));
+                }
+            },
+        }
+        let Some(key) = lookup else {
+            return Ok(None);
         };
-        self.release_shared();
-        result
+        let entry = match self.read() {
+            MapRef::Hash(h) => h.get(&key),
+            MapRef::BTree(b) => b.get(&key),
+            MapRef::Art(a) => a.get(&key),
+        };
+        Ok(entry.map(|e| (e.value(self.merge_operator.as_ref()), e.expire_at)))
+    }
+
+    fn remove_entry(&self, key: &[u8]) -> Result<Entry, Status> {
+        let guard = self.latch.exclusive();
+        match self.write(&guard) {
+            MapMut::Hash(h) => match h.get(key) {
+                Some(entry) if !entry.is_expired() => Ok(h.remove(key).unwrap()),
+                _ => Err(Status::KeyNotFound),
+            },
+            MapMut::BTree(b) => match b.get(key) {
+                Some(entry) if !entry.is_expired() => Ok(b.remove(key).unwrap()),
+                _ => Err(Status::KeyNotFound),
+            },
+            MapMut::Art(a) => match a.get(key) {
+                Some(entry) if !entry.is_expired() => Ok(a.remove(key).unwrap()),
+                _ => Err(Status::KeyNotFound),
+            },
+        }
+    }
+
+    fn remove_range_entries(
+        &self,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<BTreeMap<Vec<u8>, Entry>, Status> {
+        let guard = self.latch.exclusive();
+        match self.write(&guard) {
+            MapMut::Hash(_) => Err(Status::Error), // Hash containers are not ordered
+            MapMut::BTree(b) => {
+                if start >= end {
+                    return Ok(BTreeMap::new());
+                }
+                // Removed one by one: appending the tail back would rebuild the whole map
+                let keys: Vec<_> = b
+                    .range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+                    .map(|(k, _)| k.clone())
+                    .collect();
+                let removed = keys
+                    .into_iter()
+                    .filter_map(|k| b.remove_entry(&k))
+                    .collect();
+                Ok(removed)
+            }
+            MapMut::Art(a) => {
+                let keys: Vec<_> = a
+                    .range_from(start)
+                    .map(|(k, _)| k)
+                    .take_while(|k| k.as_slice() < end)
+                    .collect();
+                let removed = keys
+                    .into_iter()
+                    .filter_map(|k| a.remove(&k).map(|e| (k, e)))
+                    .collect();
+                Ok(removed)
+            }
+        }
+    }
+
+    fn prefix_values(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Status> {
+        let _guard = self.latch.shared();
+        match self.read() {
+            MapRef::Hash(_) => Err(Status::Error), // Hash containers are not ordered
+            MapRef::BTree(b) => {
+                let result = b
+                    .range(prefix.to_vec()..)
+                    .take_while(|(k, _)| k.starts_with(prefix))
+                    .filter(|(_, e)| !e.is_expired())
+                    .map(|(_, e)| e.value(self.merge_operator.as_ref()))
+                    .collect();
+                Ok(result)
+            }
+            MapRef::Art(a) => {
+                let result = a
+                    .range_from(prefix)
+                    .take_while(|(k, _)| k.starts_with(prefix))
+                    .filter(|(_, e)| !e.is_expired())
+                    .map(|(_, e)| e.value(self.merge_operator.as_ref()))
+                    .collect();
+                Ok(result)
+            }
+        }
+    }
+
+    fn entries_from(
+        &self,
+        start: Bound<&[u8]>,
+        limit: usize,
+        at: Instant,
+    ) -> Result<Entries, Status> {
+        let _guard = self.latch.shared();
+        let value = |(k, e): (&Vec<u8>, &Entry)| (k.clone(), e.value(self.merge_operator.as_ref()));
+        match self.read() {
+            MapRef::Hash(_) => Err(Status::Error), // Hash containers are not ordered
+            MapRef::BTree(b) => Ok(b
+                .range::<[u8], _>((start, Bound::Unbounded))
+                .filter(|(_, e)| !e.is_expired_at(at))
+                .take(limit)
+                .map(value)
+                .collect()),
+            MapRef::Art(a) => {
+                let (from, excluded) = match start {
+                    Bound::Included(k) => (k, None),
+                    Bound::Excluded(k) => (k, Some(k)),
+                    Bound::Unbounded => (&[][..], None),
+                };
+                Ok(a.range_from(from)
+                    .filter(|(k, e)| Some(k.as_slice()) != excluded && !e.is_expired_at(at))
+                    .take(limit)
+                    .map(|(k, e)| (k, e.value(self.merge_operator.as_ref())))
+                    .collect())
+            }
+        }
     }
 
-    fn update(&self, key: &[u8], val: Vec<u8>) -> Result<(), Status> {
-        self.exclusive();
-        let result = match self {
-            Storage::HashMap(_, h) => {
-                let h = unsafe { &mut *h.get() };
-                match h.get_mut(key) {
-                    Some(v) => {
-                        *v = val;
-                        Ok(())
+    fn for_each_entry(&self, at: Instant, f: &mut dyn FnMut(&[u8], &Entry)) {
+        let _guard = self.latch.shared();
+        match self.read() {
+            MapRef::Hash(h) => h
+                .iter()
+                .filter(|(_, e)| !e.is_expired_at(at))
+                .for_each(|(k, e)| f(k, e)),
+            MapRef::BTree(b) => b
+                .iter()
+                .filter(|(_, e)| !e.is_expired_at(at))
+                .for_each(|(k, e)| f(k, e)),
+            MapRef::Art(a) => a
+                .iter()
+                .filter(|(_, e)| !e.is_expired_at(at))
+                .for_each(|(k, e)| f(&k, e)),
+        }
+    }
+
+    // Skipped if the shard is latched by someone else.
+    // Copies the entries under a single latch.
+    fn entries_of(&self, keys: &[Vec<u8>], at: Instant) -> Entries {
+        let _guard = self.latch.shared();
+        let map = self.read();
+        keys.iter()
+            .filter_map(|k| {
+                let entry = match &map {
+                    MapRef::Hash(h) => h.get(k),
+                    MapRef::BTree(b) => b.get(k),
+                    MapRef::Art(a) => a.get(k),
+                };
+                let entry = entry.filter(|e| !e.is_expired_at(at))?;
+                Some((k.clone(), entry.value(self.merge_operator.as_ref())))
+            })
+            .collect()
+    }
+
+    fn remove_expired(&self, before: Instant) {
+        let Some(guard) = self.latch.try_exclusive() else {
+            return;
+        };
+        let q = self.expiries(&guard);
+        match self.write(&guard) {
+            MapMut::Hash(h) => {
+                while let Some(key) = q.pop_expired(before) {
+                    // The key might have been overwritten with a later expiry or no expiry
+                    if h.get(&key).is_some_and(|e| e.is_expired_at(before)) {
+                        h.remove(&key);
+                    }
+                }
+            }
+            MapMut::BTree(b) => {
+                while let Some(key) = q.pop_expired(before) {
+                    // The key might have been overwritten with a later expiry or no expiry
+                    if b.get(&key).is_some_and(|e| e.is_expired_at(before)) {
+                        b.remove(&key);
                     }
-                    None => Err(Status::KeyNotFound),
                 }
             }
-            Storage::BTreeMap(_, b) => {
-                let b = unsafe { &mut *b.get() };
-                match b.get_mut(key) {
-                    Some(v) => {
-                        *v = val;
-                        Ok(())
+            MapMut::Art(a) => {
+                while let Some(key) = q.pop_expired(before) {
+                    // The key might have been overwritten with a later expiry or no expiry
+                    if a.get(&key).is_some_and(|e| e.is_expired_at(before)) {
+                        a.remove(&key);
                     }
-                    None => Err(Status::KeyNotFound),
                 }
             }
+        }
+    }
+}
+
+enum Parts {
+    Shards(Vec<Shard>),
+    HashTable(Box<HashTable>),
+    SkipList(Box<SkipList>),
+}
+
+pub struct Storage {
+    c_type: ContainerType,
+    parts: Parts,
+    hasher: RandomState, // picks the shard of a key
+    merge_operator: Option<MergeOperator>,
+    indexes: RwLock<Vec<SecondaryIndex>>,
+    index_lock: Mutex<()>, // serializes the writes to an indexed container
+    versions: Vec<RwLock<VersionLog>>, // before-images kept for the live snapshots, per stripe
+    retention: Option<Duration>, // how long the past values stay readable
+    captures_changes: bool, // publishes its committed changes to the change log
+    watchers: Watchers,    // watches notified of its committed changes
+}
+
+impl Storage {
+    fn new(options: &ContainerOptions, latch_mode: LatchMode) -> Self {
+        let new_shards = |new_map: fn() -> Map| {
+            let shards = (0..options.shards())
+                .map(|_| Shard::new(new_map(), options.merge_operator(), latch_mode))
+                .collect();
+            Parts::Shards(shards)
         };
-        self.release_exclusive();
-        result
+        let parts = match options.get_type() {
+            ContainerType::Hash => new_shards(|| Map::Hash(ShardCell::new(HashMap::new()))),
+            ContainerType::ConcurrentHash => {
+                Parts::HashTable(Box::new(HashTable::new(options.merge_operator())))
+            }
+            ContainerType::BTree => new_shards(|| Map::BTree(ShardCell::new(BTreeMap::new()))),
+            ContainerType::Art => new_shards(|| Map::Art(ShardCell::new(Art::default()))),
+            ContainerType::SkipList => {
+                Parts::SkipList(Box::new(SkipList::new(options.merge_operator())))
+            }
+        };
+        // Sharded containers have a stripe per shard, so that a snapshot scan of a shard
+        // only blocks the writers of that shard
+        let stripes = match &parts {
+            Parts::Shards(shards) => shards.len(),
+            Parts::HashTable(_) | Parts::SkipList(_) => VERSION_STRIPES,
+        };
+        Storage {
+            c_type: options.get_type(),
+            parts,
+            hasher: RandomState::new(),
+            merge_operator: options.merge_operator(),
+            indexes: RwLock::new(Vec::new()),
+            index_lock: Mutex::new(()),
+            versions: (0..stripes)
+                .map(|_| RwLock::new(VersionLog::new(options.version_retention())))
+                .collect(),
+            retention: options.version_retention(),
+            captures_changes: options.change_capture(),
+            watchers: Watchers::default(),
+        }
     }
 
-    fn remove(&self, key: &[u8]) -> Result<(), Status> {
-        self.shared();
-        let result = match self {
-            Storage::HashMap(_, h) => {
-                let h = unsafe { &mut *h.get() };
-                match h.remove(key) {
-                    Some(_) => Ok(()),
-                    None => Err(Status::KeyNotFound),
+    // Whether the transactions writing the container track their changes, for the change
+    // log or for the watches.
+    fn tracks_changes(&self) -> bool {
+        self.captures_changes || !self.watchers.is_empty()
+    }
+
+    // The part holding the key. Range operations are also sent to the part of their
+    // start key: ordered containers have a single part, and all the parts of a Hash
+    // container reject range operations.
+    fn part(&self, key: &[u8]) -> &dyn EntryStore {
+        match &self.parts {
+            Parts::Shards(shards) => &shards[self.shard_index(key)],
+            Parts::HashTable(table) => table.as_ref(),
+            Parts::SkipList(skiplist) => skiplist.as_ref(),
+        }
+    }
+
+    fn shard_index(&self, key: &[u8]) -> usize {
+        match self.shards().len() {
+            0 | 1 => 0,
+            n => self.hasher.hash_one(key) as usize % n,
+        }
+    }
+
+    // The earliest time a read may see the keys as of. Keys that expired since then are
+    // kept, so that reads at a past time within the retention window still see them.
+    fn retained_since(&self) -> Instant {
+        let now = Instant::now();
+        self.retention
+            .and_then(|retention| now.checked_sub(retention))
+            .unwrap_or(now)
+    }
+
+    // The before-image of a write to a key that does not exist or has expired, which
+    // reads at a past time see if it had not expired then.
+    fn expired_image(&self, key: &[u8]) -> BeforeImage {
+        self.part(key).get_at(key, self.retained_since()).ok()
+    }
+
+    // The stripe of the version log that holds the versions of the key. It is the shard
+    // of the key in a sharded container.
+    fn stripe(&self, key: &[u8]) -> usize {
+        match self.versions.len() {
+            1 => 0,
+            n => self.hasher.hash_one(key) as usize % n,
+        }
+    }
+
+    // The stripes of the version log that hold the versions of the keys of a part.
+    fn part_stripes(&self, part: usize) -> Range<usize> {
+        match &self.parts {
+            Parts::Shards(_) => part..part + 1,
+            Parts::HashTable(_) | Parts::SkipList(_) => 0..self.versions.len(),
+        }
+    }
+
+    fn all_parts(&self) -> Vec<&dyn EntryStore> {
+        match &self.parts {
+            Parts::Shards(shards) => shards.iter().map(|s| s as &dyn EntryStore).collect(),
+            Parts::HashTable(table) => vec![table.as_ref()],
+            Parts::SkipList(skiplist) => vec![skiplist.as_ref()],
+        }
+    }
+
+    // Empty for ConcurrentHash and SkipList containers
+    fn shards(&self) -> &[Shard] {
+        match &self.parts {
+            Parts::Shards(shards) => shards,
+            Parts::HashTable(_) | Parts::SkipList(_) => &[],
+        }
+    }
+
+    fn get_with_expiry(&self, key: &[u8]) -> Result<(Vec<u8>, Option<Instant>), Status> {
+        self.part(key).get_with_expiry(key)
+    }
+
+    fn prefix_values(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Status> {
+        self.part(prefix).prefix_values(prefix)
+    }
+
+    // Indexes the existing entries with the new index and starts maintaining it.
+    fn add_index(&self, index: SecondaryIndex) {
+        // Writers wait for the indexes lock, so the parts can be visited one at a time
+        let mut indexes = self.indexes.write().unwrap();
+        for part in self.all_parts() {
+            part.for_each_entry(Instant::now(), &mut |k, e| {
+                index.insert(k, &e.value(self.merge_operator.as_ref()), e.expire_at);
+            });
+        }
+        indexes.push(index);
+    }
+
+    // The following functions modify the container and its secondary indexes.
+    // Writes to an indexed container are serialized by index_lock so that the
+    // indexes observe the writes in the same order as the container.
+
+    fn clear_indexed(&self) {
+        let indexes = self.indexes.read().unwrap();
+        let _guard = self.index_lock.lock().unwrap();
+        for part in self.all_parts() {
+            part.clear_entries();
+        }
+        for index in indexes.iter() {
+            index.clear();
+        }
+    }
+
+    fn insert_indexed(
+        &self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        expire_at: Option<Instant>,
+    ) -> Result<(), Status> {
+        let indexes = self.indexes.read().unwrap();
+        if indexes.is_empty() {
+            return self.part(&key).insert_entry(key, val, expire_at);
+        }
+        let _guard = self.index_lock.lock().unwrap();
+        self.part(&key)
+            .insert_entry(key.clone(), val.clone(), expire_at)?;
+        for index in indexes.iter() {
+            index.insert(&key, &val, expire_at);
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Vec<u8>, Status> {
+        self.get_with_expiry(key).map(|(val, _)| val)
+    }
+
+    fn before_image(&self, entry: &Entry) -> BeforeImage {
+        Some((entry.value(self.merge_operator.as_ref()), entry.expire_at))
+    }
+
+    // The image of a write to publish when its transaction commits, if the container
+    // tracks its changes.
+    fn after_image(&self, image: impl FnOnce() -> AfterImage) -> Option<AfterImage> {
+        self.tracks_changes().then(image)
+    }
+
+    // Sets the value of the key whether it exists or not.
+    fn put(&self, key: Vec<u8>, val: Vec<u8>, expire_at: Option<Instant>) {
+        if self.update(&key, val.clone(), expire_at).is_err() {
+            let _ = self.insert(key, val, expire_at);
+        }
+    }
+
+    // Returns the replaced entry.
+    fn update_indexed(
+        &self,
+        key: &[u8],
+        val: Vec<u8>,
+        expire_at: Option<Instant>,
+    ) -> Result<Entry, Status> {
+        let indexes = self.indexes.read().unwrap();
+        if indexes.is_empty() {
+            return self.part(key).update_entry(key, val, expire_at);
+        }
+        let _guard = self.index_lock.lock().unwrap();
+        let old = self.part(key).update_entry(key, val.clone(), expire_at)?;
+        let old_val = old.value(self.merge_operator.as_ref());
+        for index in indexes.iter() {
+            index.remove(key, &old_val);
+            index.insert(key, &val, expire_at);
+        }
+        Ok(old)
+    }
+
+    // Returns the value after the merge if image is set. Indexed containers always
+    // return it.
+    fn merge_indexed(
+        &self,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+        image: bool,
+    ) -> Result<AfterImage, Status> {
+        let indexes = self.indexes.read().unwrap();
+        if indexes.is_empty() {
+            return self.part(&key).merge_entry(key, operand, image);
+        }
+        let _guard = self.index_lock.lock().unwrap();
+        let old = self.get(&key).ok();
+        let after = self.part(&key).merge_entry(key.clone(), operand, true)?;
+        if let Some((new_val, expire_at)) = &after {
+            for index in indexes.iter() {
+                if let Some(old_val) = &old {
+                    index.remove(&key, old_val);
                 }
+                index.insert(&key, new_val, *expire_at);
             }
-            Storage::BTreeMap(_, b) => {
-                let b = unsafe { &mut *b.get() };
-                match b.remove(key) {
-                    Some(_) => Ok(()),
-                    None => Err(Status::KeyNotFound),
+        }
+        Ok(after)
+    }
+
+    // Returns the removed entry.
+    fn remove_indexed(&self, key: &[u8]) -> Result<Entry, Status> {
+        let indexes = self.indexes.read().unwrap();
+        if indexes.is_empty() {
+            return self.part(key).remove_entry(key);
+        }
+        let _guard = self.index_lock.lock().unwrap();
+        let old = self.part(key).remove_entry(key)?;
+        let old_val = old.value(self.merge_operator.as_ref());
+        for index in indexes.iter() {
+            index.remove(key, &old_val);
+        }
+        Ok(old)
+    }
+
+    // Returns the removed entries. Some of them may have expired.
+    fn remove_range_indexed(
+        &self,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<BTreeMap<Vec<u8>, Entry>, Status> {
+        let indexes = self.indexes.read().unwrap();
+        if indexes.is_empty() {
+            return self.part(start).remove_range_entries(start, end);
+        }
+        let _guard = self.index_lock.lock().unwrap();
+        let removed = self.part(start).remove_range_entries(start, end)?;
+        for (key, entry) in removed.iter().filter(|(_, e)| !e.is_expired()) {
+            let val = entry.value(self.merge_operator.as_ref());
+            for index in indexes.iter() {
+                index.remove(key, &val);
+            }
+        }
+        Ok(removed)
+    }
+
+    // The following functions wrap the ones above. While snapshots of the container are
+    // alive or it retains its versions, a write locks the stripe of the version log of its
+    // key exclusively and records its before-image there. It waits for the snapshot reads
+    // of that stripe and for the other writers of the stripe, but not for the writers of
+    // other stripes.
+
+    // Locks the stripes of the version log in the range, in order, for a write.
+    fn lock_versions(&self, stripes: Range<usize>) -> VersionsGuard<'_> {
+        let first = stripes.start;
+        let logs: Vec<_> = self.versions[stripes.clone()]
+            .iter()
+            .map(|log| log.read().unwrap())
+            .collect();
+        if !logs.iter().any(|log| log.is_recording()) {
+            return VersionsGuard::Shared(logs);
+        }
+        drop(logs);
+        let logs = self.versions[stripes]
+            .iter()
+            .map(|log| log.write().unwrap())
+            .collect();
+        VersionsGuard::Exclusive(first, logs)
+    }
+
+    fn lock_key_versions(&self, key: &[u8]) -> VersionsGuard<'_> {
+        let stripe = self.stripe(key);
+        self.lock_versions(stripe..stripe + 1)
+    }
+
+    fn lock_all_versions(&self) -> VersionsGuard<'_> {
+        self.lock_versions(0..self.versions.len())
+    }
+
+    // Records the before-image of a write if the version log is locked for recording.
+    fn record_version(
+        &self,
+        versions: &mut VersionsGuard<'_>,
+        key: &[u8],
+        before: impl FnOnce() -> BeforeImage,
+    ) {
+        if let VersionsGuard::Exclusive(first, logs) = versions {
+            logs[self.stripe(key) - *first].record(key, before);
+        }
+    }
+
+    // Read-locks the stripes of the version log in the range, in order.
+    fn read_versions(&self, stripes: Range<usize>) -> ReadVersions<'_> {
+        ReadVersions {
+            storage: self,
+            first: stripes.start,
+            logs: self.versions[stripes]
+                .iter()
+                .map(|log| log.read().unwrap())
+                .collect(),
+        }
+    }
+
+    // Returns the removed keys with their before-images if collect is set.
+    fn clear(&self, collect: bool) -> Vec<(Vec<u8>, BeforeImage)> {
+        let mut versions = self.lock_all_versions();
+        let mut before = Vec::new();
+        if collect || versions.is_recording() {
+            let at = self.retained_since();
+            for part in self.all_parts() {
+                part.for_each_entry(at, &mut |k, e| {
+                    before.push((k.to_vec(), self.before_image(e)))
+                });
+            }
+        }
+        self.clear_indexed();
+        for (key, image) in &before {
+            self.record_version(&mut versions, key, || image.clone());
+        }
+        if !collect {
+            before.clear();
+        }
+        before
+    }
+
+    fn insert(&self, key: Vec<u8>, val: Vec<u8>, expire_at: Option<Instant>) -> Result<(), Status> {
+        let mut versions = self.lock_key_versions(&key);
+        if !versions.is_recording() {
+            return self.insert_indexed(key, val, expire_at);
+        }
+        let before = self.expired_image(&key);
+        self.insert_indexed(key.clone(), val, expire_at)?;
+        self.record_version(&mut versions, &key, || before);
+        Ok(())
+    }
+
+    fn update(
+        &self,
+        key: &[u8],
+        val: Vec<u8>,
+        expire_at: Option<Instant>,
+    ) -> Result<Entry, Status> {
+        let mut versions = self.lock_key_versions(key);
+        let old = self.update_indexed(key, val, expire_at)?;
+        self.record_version(&mut versions, key, || self.before_image(&old));
+        Ok(old)
+    }
+
+    fn merge(&self, key: Vec<u8>, operand: Vec<u8>, image: bool) -> Result<AfterImage, Status> {
+        let mut versions = self.lock_key_versions(&key);
+        if !versions.is_recording() {
+            return self.merge_indexed(key, operand, image);
+        }
+        // The key may have expired, in which case the operand replaces it
+        let before = self.expired_image(&key);
+        let after = self.merge_indexed(key.clone(), operand, image)?;
+        self.record_version(&mut versions, &key, || before);
+        Ok(after)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Entry, Status> {
+        let mut versions = self.lock_key_versions(key);
+        let old = self.remove_indexed(key)?;
+        self.record_version(&mut versions, key, || self.before_image(&old));
+        Ok(old)
+    }
+
+    fn remove_range(&self, start: &[u8], end: &[u8]) -> Result<BTreeMap<Vec<u8>, Entry>, Status> {
+        let mut versions = self.lock_all_versions();
+        let removed = self.remove_range_indexed(start, end)?;
+        for (key, entry) in removed.iter() {
+            self.record_version(&mut versions, key, || self.before_image(entry));
+        }
+        Ok(removed)
+    }
+
+    // The following functions read the container as of the read point. The stripes of the
+    // version log of the keys read are read-locked while the container is read, so no
+    // write can be applied between reading a key and checking whether it was written
+    // since the snapshot.
+
+    fn snapshot_get(&self, at: &ReadPoint, key: &[u8]) -> Result<Vec<u8>, Status> {
+        let versions = self.versions[self.stripe(key)].read().unwrap();
+        let now = at.instant();
+        match versions.get(key, at) {
+            Some(before) => visible_value(before, now).ok_or(Status::KeyNotFound),
+            None => self.part(key).get_at(key, now).map(|(val, _)| val),
+        }
+    }
+
+    fn snapshot_cursor(&self, start: Bound<Vec<u8>>) -> SnapshotCursor {
+        match self.c_type {
+            ContainerType::Hash | ContainerType::ConcurrentHash => SnapshotCursor::Part(0, None),
+            ContainerType::BTree | ContainerType::Art | ContainerType::SkipList => {
+                SnapshotCursor::From(start)
+            }
+        }
+    }
+
+    // Copies the next batch of entries of the snapshot and advances the cursor. At most
+    // SNAPSHOT_SCAN_BATCH keys are copied at a time, so writers are only blocked while a
+    // batch is copied, or while the keys of a part are listed, and only the writers of the
+    // stripes read. Returns None at the end of the container.
+    fn snapshot_batch(&self, at: &ReadPoint, cursor: &mut SnapshotCursor) -> Option<Entries> {
+        let now = at.instant();
+        let before_value =
+            |(k, before): (&Vec<u8>, &BeforeImage)| Some((k.clone(), visible_value(before, now)?));
+        match cursor {
+            SnapshotCursor::Part(i, keys) => {
+                let part = *self.all_parts().get(*i)?;
+                let versions = self.read_versions(self.part_stripes(*i));
+                // The keys of the part are listed once: its current keys not written since
+                // the snapshot, and the keys written since, which include the deleted ones.
+                let keys = keys.get_or_insert_with(|| {
+                    let mut keys = Vec::new();
+                    part.for_each_entry(now, &mut |k, _| {
+                        if versions.get(k, at).is_none() {
+                            keys.push(k.to_vec());
+                        }
+                    });
+                    let all = (Bound::Unbounded, Bound::Unbounded);
+                    keys.extend(versions.range(all, at).map(|(k, _)| k.clone()));
+                    keys.into_iter()
+                });
+                let batch: Vec<_> = keys.take(SNAPSHOT_SCAN_BATCH).collect();
+                if batch.is_empty() {
+                    *cursor = SnapshotCursor::Part(*i + 1, None);
+                    return Some(Vec::new());
                 }
+                // Keys written since the snapshot are taken from the version log
+                let (written, current): (Vec<_>, Vec<_>) = batch
+                    .into_iter()
+                    .partition(|k| versions.get(k, at).is_some());
+                let mut entries = part.entries_of(&current, now);
+                entries.extend(
+                    written
+                        .iter()
+                        .filter_map(|k| before_value((k, versions.get(k, at)?))),
+                );
+                Some(entries)
             }
-        };
-        self.release_shared();
-        result
+            SnapshotCursor::From(start) => {
+                let versions = self.read_versions(0..self.versions.len());
+                let current = self
+                    .part(&[])
+                    .entries_from(start.as_ref().map(Vec::as_slice), SNAPSHOT_SCAN_BATCH, now)
+                    .ok()?;
+                // The batch ends at its last key unless it reached the end of the container
+                let end = match current.last() {
+                    Some((k, _)) if current.len() == SNAPSHOT_SCAN_BATCH => {
+                        Bound::Included(k.clone())
+                    }
+                    _ => Bound::Unbounded,
+                };
+                let mut entries: BTreeMap<_, _> = current
+                    .into_iter()
+                    .filter(|(k, _)| versions.get(k, at).is_none())
+                    .collect();
+                let range = (
+                    start.as_ref().map(Vec::as_slice),
+                    end.as_ref().map(Vec::as_slice),
+                );
+                entries.extend(versions.range(range, at).filter_map(before_value));
+                *cursor = match end {
+                    Bound::Included(k) => SnapshotCursor::From(Bound::Excluded(k)),
+                    _ => SnapshotCursor::Done,
+                };
+                Some(entries.into_iter().collect())
+            }
+            SnapshotCursor::Done => None,
+        }
+    }
+
+    // Physically removes the expired keys and the versions that can no longer be read.
+    // Shards that are latched by someone else (e.g. by a live iterator) are skipped and
+    // retried in the next round.
+    fn remove_expired(&self) {
+        for log in &self.versions {
+            log.write().unwrap().prune();
+        }
+        let before = self.retained_since();
+        for part in self.all_parts() {
+            part.remove_expired(before);
+        }
+    }
+
+    fn iter(self: &Arc<Self>) -> InMemIterator {
+        match self.c_type {
+            ContainerType::ConcurrentHash => return InMemIterator::buckets(Arc::clone(self)),
+            ContainerType::SkipList => return InMemIterator::skiplist(Arc::clone(self)),
+            ContainerType::Hash | ContainerType::BTree | ContainerType::Art => {}
+        }
+        // Latch all the shards while iterator is alive. The latches are released when the iterator is dropped.
+        // Safety: the guards are stored in the iterator together with the Arc of the storage and are
+        // dropped before the Arc, so the latches outlive the guards.
+        let storage: &'static Storage = unsafe { &*Arc::as_ptr(self) };
+        let guards: Vec<SharedGuard<'static>> = storage
+            .shards()
+            .iter()
+            .map(|shard| shard.latch.shared())
+            .collect();
+        match storage.shards()[0].read() {
+            MapRef::BTree(b) => InMemIterator::btree(Arc::clone(self), guards, b.iter()),
+            MapRef::Art(a) => InMemIterator::Art(Mutex::new(a.iter()), guards, Arc::clone(self)),
+            MapRef::Hash(_) => {
+                let iters: Vec<_> = storage
+                    .shards()
+                    .iter()
+                    .map(|shard| match shard.read() {
+                        MapRef::Hash(h) => h.iter(),
+                        _ => unreachable!("ordered containers have a single shard"),
+                    })
+                    .collect();
+                InMemIterator::hash(Arc::clone(self), guards, iters.into_iter().flatten())
+            }
+        }
+    }
+}
+
+// The value of a before-image, unless the key did not exist or had expired at the time.
+fn visible_value(before: &BeforeImage, at: Instant) -> Option<Vec<u8>> {
+    match before {
+        Some((val, expire_at)) if expire_at.is_none_or(|t| t > at) => Some(val.clone()),
+        _ => None,
+    }
+}
+
+// Stripes of the version log locked for a write, the first one's index with the
+// exclusive locks. Writes to a container without live snapshots or retention share the
+// locks and are not recorded.
+enum VersionsGuard<'a> {
+    Shared(Vec<RwLockReadGuard<'a, VersionLog>>),
+    Exclusive(usize, Vec<RwLockWriteGuard<'a, VersionLog>>),
+}
+
+impl VersionsGuard<'_> {
+    fn is_recording(&self) -> bool {
+        match self {
+            VersionsGuard::Shared(logs) => logs.iter().any(|log| log.is_recording()),
+            VersionsGuard::Exclusive(_, logs) => logs.iter().any(|log| log.is_recording()),
+        }
+    }
+}
+
+// Stripes of the version log read-locked by a snapshot read, from stripe `first` on.
+struct ReadVersions<'a> {
+    storage: &'a Storage,
+    first: usize,
+    logs: Vec<RwLockReadGuard<'a, VersionLog>>,
+}
+
+impl ReadVersions<'_> {
+    fn get(&self, key: &[u8], at: &ReadPoint) -> Option<&BeforeImage> {
+        self.logs[self.storage.stripe(key) - self.first].get(key, at)
+    }
+
+    // The keys of the stripes in the range written since the point, unordered.
+    fn range<'a>(
+        &'a self,
+        range: (Bound<&'a [u8]>, Bound<&'a [u8]>),
+        at: &'a ReadPoint,
+    ) -> impl Iterator<Item = (&'a Vec<u8>, &'a BeforeImage)> {
+        self.logs.iter().flat_map(move |log| log.range(range, at))
+    }
+}
+
+/// A container read by a snapshot or at a past time. The versions retained for a
+/// snapshot are released when the snapshot and its iterators are dropped.
+pub struct SnapshotPin {
+    storage: Arc<Storage>,
+    at: ReadPoint,
+}
+
+impl SnapshotPin {
+    fn get(&self, key: &[u8]) -> Result<Vec<u8>, Status> {
+        self.storage.snapshot_get(&self.at, key)
     }
 
-    fn iter(self: &Arc<Self>) -> InMemIterator {
-        self.shared(); // Latch the storage while iterator is alive. When iterator is dropped, the latch must be released.
-        match self.as_ref() {
-            Storage::HashMap(_, h) => {
-                let h = unsafe { &*h.get() };
-                InMemIterator::hash(Arc::clone(self), h.iter())
-            }
-            Storage::BTreeMap(_, b) => {
-                let b = unsafe { &*b.get() };
-                InMemIterator::btree(Arc::clone(self), b.iter())
+    // Whether the container still has the versions to read at a past time.
+    fn is_readable(&self) -> bool {
+        match self.at {
+            ReadPoint::Epoch(_) => true,
+            ReadPoint::Time(time) => self.storage.versions[0].read().unwrap().retains(time),
+        }
+    }
+}
+
+impl Drop for SnapshotPin {
+    fn drop(&mut self) {
+        if let ReadPoint::Epoch(epoch) = self.at {
+            for log in &self.storage.versions {
+                log.write().unwrap().unpin(epoch);
             }
         }
     }
 }
 
+// Position of a snapshot scan: the current part of an unordered container with its keys
+// left to copy once they are listed, or the start of the next batch of an ordered one.
+enum SnapshotCursor {
+    Part(usize, Option<std::vec::IntoIter<Vec<u8>>>),
+    From(Bound<Vec<u8>>),
+    Done,
+}
+
+// The position of a snapshot scan and the entries copied from the current batch.
+pub struct SnapshotIter {
+    cursor: SnapshotCursor,
+    entries: std::vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+}
+
+type HashIter = std::iter::Flatten<
+    std::vec::IntoIter<std::collections::hash_map::Iter<'static, Vec<u8>, Entry>>,
+>;
+
+// The number of buckets when the scan began, the next bucket and the entries copied from
+// the current one.
+type BucketsIter = (usize, usize, std::vec::IntoIter<(Vec<u8>, Vec<u8>)>);
+
 pub enum InMemIterator {
-    // Storage and the iterator
-    Hash(
+    // The iterator, the latch guards of the shards and the storage. Fields are dropped in
+    // this order, so the latches are released before the storage can be freed.
+    Hash(Mutex<HashIter>, Vec<SharedGuard<'static>>, Arc<Storage>),
+    BTree(
+        Mutex<std::collections::btree_map::Iter<'static, Vec<u8>, Entry>>,
+        Vec<SharedGuard<'static>>,
         Arc<Storage>,
-        Mutex<std::collections::hash_map::Iter<'static, Vec<u8>, Vec<u8>>>,
     ),
-    BTree(
+    Art(
+        Mutex<art::Iter<'static>>,
+        Vec<SharedGuard<'static>>,
         Arc<Storage>,
-        Mutex<std::collections::btree_map::Iter<'static, Vec<u8>, Vec<u8>>>,
     ),
+    // Scans a ConcurrentHash container one bucket at a time without holding any lock
+    // between next() calls.
+    Buckets(Mutex<BucketsIter>, Arc<Storage>),
+    // Scans a SkipList container without holding any latch. Holds the last returned key;
+    // next() continues from the first key after it.
+    SkipList(Mutex<Option<Vec<u8>>>, Arc<Storage>),
+    // Scans a container as of a snapshot, one batch at a time without holding any latch
+    // between next() calls.
+    Snapshot(Mutex<SnapshotIter>, Arc<SnapshotPin>),
 }
 
-impl Drop for InMemIterator {
-    fn drop(&mut self) {
-        match self {
-            InMemIterator::Hash(storage, _) => storage.release_shared(),
-            InMemIterator::BTree(storage, _) => storage.release_shared(),
-        }
+impl InMemIterator {
+    fn skiplist(storage: Arc<Storage>) -> Self {
+        InMemIterator::SkipList(Mutex::new(None), storage)
     }
-}
 
-impl InMemIterator {
-    fn hash(
-        storage: Arc<Storage>,
-        iter: std::collections::hash_map::Iter<'static, Vec<u8>, Vec<u8>>,
-    ) -> Self {
-        InMemIterator::Hash(storage, Mutex::new(iter))
+    fn snapshot(pin: Arc<SnapshotPin>, start: Bound<Vec<u8>>) -> Self {
+        let iter = SnapshotIter {
+            cursor: pin.storage.snapshot_cursor(start),
+            entries: Vec::new().into_iter(),
+        };
+        InMemIterator::Snapshot(Mutex::new(iter), pin)
+    }
+
+    fn buckets(storage: Arc<Storage>) -> Self {
+        let Parts::HashTable(table) = &storage.parts else {
+            unreachable!("bucket iterator over a container without buckets");
+        };
+        let stride = table.buckets();
+        InMemIterator::Buckets(Mutex::new((stride, 0, Vec::new().into_iter())), storage)
+    }
+
+    fn hash(storage: Arc<Storage>, guards: Vec<SharedGuard<'static>>, iter: HashIter) -> Self {
+        InMemIterator::Hash(Mutex::new(iter), guards, storage)
     }
 
     fn btree(
         storage: Arc<Storage>,
-        iter: std::collections::btree_map::Iter<'static, Vec<u8>, Vec<u8>>,
+        guards: Vec<SharedGuard<'static>>,
+        iter: std::collections::btree_map::Iter<'static, Vec<u8>, Entry>,
     ) -> Self {
-        InMemIterator::BTree(storage, Mutex::new(iter))
+        InMemIterator::BTree(Mutex::new(iter), guards, storage)
     }
 
+    // Expired keys are skipped
     fn next(&self) -> Option<(Vec<u8>, Vec<u8>)> {
         match self {
-            InMemIterator::Hash(_, iter) => {
+            InMemIterator::Hash(iter, _, storage) => {
                 let mut iter = iter.lock().unwrap();
-                iter.next().map(|(k, v)| (k.clone(), v.clone()))
+                iter.find(|(_, e)| !e.is_expired())
+                    .map(|(k, e)| (k.clone(), e.value(storage.merge_operator.as_ref())))
             }
-            InMemIterator::BTree(_, iter) => {
+            InMemIterator::BTree(iter, _, storage) => {
                 let mut iter = iter.lock().unwrap();
-                iter.next().map(|(k, v)| (k.clone(), v.clone()))
+                iter.find(|(_, e)| !e.is_expired())
+                    .map(|(k, e)| (k.clone(), e.value(storage.merge_operator.as_ref())))
+            }
+            InMemIterator::Art(iter, _, storage) => {
+                let mut iter = iter.lock().unwrap();
+                iter.find(|(_, e)| !e.is_expired())
+                    .map(|(k, e)| (k, e.value(storage.merge_operator.as_ref())))
+            }
+            InMemIterator::Buckets(state, storage) => {
+                let Parts::HashTable(table) = &storage.parts else {
+                    unreachable!("bucket iterator over a container without buckets");
+                };
+                let mut state = state.lock().unwrap();
+                let (stride, next_bucket, entries) = &mut *state;
+                loop {
+                    if let Some(entry) = entries.next() {
+                        return Some(entry);
+                    }
+                    if *next_bucket == *stride {
+                        return None;
+                    }
+                    *entries = table.bucket_entries(*stride, *next_bucket).into_iter();
+                    *next_bucket += 1;
+                }
+            }
+            InMemIterator::SkipList(last_key, storage) => {
+                let Parts::SkipList(skiplist) = &storage.parts else {
+                    unreachable!("SkipList iterator over a sharded container");
+                };
+                let mut last_key = last_key.lock().unwrap();
+                let (key, val) = skiplist.next_after(last_key.as_deref())?;
+                *last_key = Some(key.clone());
+                Some((key, val))
+            }
+            InMemIterator::Snapshot(iter, pin) => {
+                let mut iter = iter.lock().unwrap();
+                let SnapshotIter { cursor, entries } = &mut *iter;
+                loop {
+                    if let Some(entry) = entries.next() {
+                        return Some(entry);
+                    }
+                    *entries = pin.storage.snapshot_batch(&pin.at, cursor)?.into_iter();
+                }
             }
         }
     }
@@ -235,28 +1412,38 @@ impl InMemIterator {
 
 /// Assumptions of InMemStorage:
 /// 1. Creation and deletion of the database is not thread-safe. This means, you can't create
-/// or delete a database while other threads are accessing the database.
+///    or delete a database while other threads are accessing the database.
 /// 2. Creation and deletion of a container is thread-safe with respect to other containers.
-/// However, deletion of a container is not thread-safe with respect to other threads accessing
-/// the same container that is being deleted. You have to make sure that no other threads are
-/// accessing the container while you are deleting. You also have to make sure that before you
-/// access the container, the container is already created (the create_container() has returned
-/// without error). If you try to access a container that is not created, it will panic as
-/// there is no container at that index in the containers vector.
+///    However, deletion of a container is not thread-safe with respect to other threads accessing
+///    the same container that is being deleted. You have to make sure that no other threads are
+///    accessing the container while you are deleting. You also have to make sure that before you
+///    access the container, the container is already created (the create_container() has returned
+///    without error). If you try to access a container that is not created, it will panic as
+///    there is no container at that index in the containers vector.
 /// 3. Accessing the container must be thread-safe. This means, you can concurrently access
-/// the container from multiple threads. insert, get, update, remove, scan_range, iter_next
-/// should be thread-safe. In the case of InMemStorage, while iterator is alive, insert,
-/// update, remove should be blocked. get and scan_range should be allowed because they are
-/// read-only operations.
+///    the container from multiple threads. insert, get, update, remove, scan_range, iter_next
+///    should be thread-safe. In the case of InMemStorage, while iterator is alive, insert,
+///    update, remove should be blocked. get and scan_range should be allowed because they are
+///    read-only operations, also on the thread that created the iterator while a writer is
+///    waiting with `LatchMode::Blocking`. ConcurrentHash and SkipList containers are the exception: their
+///    iterators do not block writers. Neither do the iterators of snapshots.
 /// 4. For simplicity, a single database can be created. If you try to create multiple databases,
-/// it will return DBExists error.
+///    it will return DBExists error.
 /// 5. The iterator next() must not be called using multiple threads. next() is not thread-safe with
-/// respect to other next() calls of the same iterator. However, next() is thread-safe with respect
-/// to other operations on the same container including next() of other iterators.
+///    respect to other next() calls of the same iterator. However, next() is thread-safe with respect
+///    to other operations on the same container including next() of other iterators.
+///    Iterators are not `Send`: the latches they hold are released by the thread that
+///    created them.
 pub struct InMemStorage {
     db_created: UnsafeCell<bool>,
     container_lock: RwLock<()>, // lock for container operations
     containers: UnsafeCell<Vec<Arc<Storage>>>, // Storage is in a Box in order to prevent moving when resizing the vector
+    latch_mode: LatchMode,                     // how the container latches wait
+    sweeper: Mutex<Option<Sweeper>>,           // background thread removing expired keys
+    next_txn_id: AtomicU64,
+    txns: Mutex<HashMap<TxnId, Weak<TxnShared>>>, // transactions not committed or aborted yet
+    prepared: Mutex<HashMap<TxnId, Arc<TxnShared>>>, // kept even if their handle is dropped
+    changes: Arc<ChangeLog>,                      // committed changes published to the subscribers
 }
 
 unsafe impl Sync for InMemStorage {}
@@ -269,25 +1456,322 @@ impl Default for InMemStorage {
 
 impl InMemStorage {
     pub fn new() -> Self {
+        Self::with_latch_mode(LatchMode::default())
+    }
+
+    /// Create a storage whose container latches wait in the given mode. See `LatchMode`.
+    pub fn with_latch_mode(latch_mode: LatchMode) -> Self {
         InMemStorage {
             db_created: UnsafeCell::new(false),
             container_lock: RwLock::new(()),
             containers: UnsafeCell::new(Vec::new()),
+            latch_mode,
+            sweeper: Mutex::new(None),
+            next_txn_id: AtomicU64::new(1),
+            txns: Mutex::new(HashMap::new()),
+            prepared: Mutex::new(HashMap::new()),
+            changes: Arc::new(ChangeLog::default()),
+        }
+    }
+
+    /// Start a background thread that removes expired keys every `interval`.
+    /// The thread is owned by the storage and stops when the storage is dropped.
+    /// Calling this again replaces the running sweeper.
+    pub fn start_sweeper(self: &Arc<Self>, interval: Duration) {
+        let sweeper = Sweeper::spawn(Arc::downgrade(self), interval);
+        *self.sweeper.lock().unwrap() = Some(sweeper);
+    }
+
+    /// Remove the expired keys from all the containers. Expired keys are already invisible
+    /// to reads; this reclaims their memory. Containers that keep their versions keep the
+    /// keys that expired within the retention window, which reads at a past time may see.
+    pub fn sweep_expired(&self) {
+        let _guard = self.container_lock.read().unwrap();
+        let containers = unsafe { &*self.containers.get() };
+        for storage in containers.iter() {
+            storage.remove_expired();
+        }
+    }
+
+    /// The transactions that are neither committed nor aborted, oldest first. Transactions
+    /// whose handle was dropped are not listed, except prepared ones, which stay until they
+    /// are committed or aborted through `prepared_txn`.
+    pub fn active_transactions(&self) -> Vec<TxnInfo> {
+        let mut txns = self.txns.lock().unwrap();
+        txns.retain(|_, txn| txn.strong_count() > 0);
+        let now = Instant::now();
+        let mut active: Vec<_> = txns
+            .values()
+            .filter_map(|txn| txn.upgrade())
+            .map(|txn| {
+                TxnInfo::new(
+                    txn.id,
+                    now.duration_since(txn.begin_time),
+                    *txn.state.lock().unwrap(),
+                    txn.writes.load(Ordering::Relaxed),
+                )
+            })
+            .collect();
+        active.sort_by_key(|info| info.txn_id());
+        active
+    }
+
+    /// A new handle to a prepared transaction, to commit or abort it with `commit_txn` or
+    /// `abort_txn` after its original handle was lost. Fails with TxNotFound unless the
+    /// transaction is prepared.
+    pub fn prepared_txn(&self, txn_id: TxnId) -> Result<InMemDummyTxnHandle, StorageError> {
+        let shared = self
+            .prepared
+            .lock()
+            .unwrap()
+            .get(&txn_id)
+            .cloned()
+            .ok_or_else(|| StorageError::new(Status::TxNotFound).with_txn(txn_id))?;
+        self.txns
+            .lock()
+            .unwrap()
+            .insert(txn_id, Arc::downgrade(&shared));
+        Ok(InMemDummyTxnHandle {
+            two_phase: true,
+            shared,
+            snapshot: None,
+        })
+    }
+
+    /// Subscribe to the committed changes of the containers created with
+    /// `ContainerOptions::with_change_capture`, from `from` on. Use `change_position` to
+    /// only receive the changes committed from now on, and `ChangeStream::position` to
+    /// resume a stream later. Changes of aborted transactions are not published.
+    pub fn subscribe(&self, from: ChangePosition) -> Result<ChangeStream, StorageError> {
+        self.changes.subscribe(from)
+    }
+
+    /// Position of the next committed change.
+    pub fn change_position(&self) -> ChangePosition {
+        self.changes.end()
+    }
+
+    /// Watch a key or the keys with a prefix of the container. See `Watch`.
+    pub fn watch(&self, c_id: &ContainerId, target: WatchTarget) -> Result<Watch, StorageError> {
+        Ok(Watch::new(self.container(c_id)?, target, None))
+    }
+
+    /// Watch a key or the keys with a prefix of the container, calling `callback` with
+    /// each event on the committing thread. See `Watch`.
+    pub fn watch_with_callback(
+        &self,
+        c_id: &ContainerId,
+        target: WatchTarget,
+        callback: impl Fn(&WatchEvent) + Send + Sync + 'static,
+    ) -> Result<Watch, StorageError> {
+        Ok(Watch::new(
+            self.container(c_id)?,
+            target,
+            Some(Box::new(callback)),
+        ))
+    }
+
+    fn container(&self, c_id: &ContainerId) -> Result<Arc<Storage>, StorageError> {
+        let _guard = self.container_lock.read().unwrap();
+        let containers = unsafe { &*self.containers.get() };
+        containers
+            .get(*c_id as usize)
+            .cloned()
+            .ok_or_else(|| StorageError::new(Status::ContainerNotFound).with_container(*c_id))
+    }
+
+    // The net changes of the transaction to the containers that track their changes, in
+    // the order of their first write. Keys whose value did not change are skipped.
+    // The old value is taken from the key's first write and the new one from the
+    // after-image of its latest write, as captured when the key was written.
+    fn committed_changes(&self, txn: &InMemDummyTxnHandle) -> Vec<PendingChange> {
+        let undo = txn.shared.undo.lock().unwrap();
+        let mut positions = HashMap::new();
+        let mut images: Vec<(&UndoRecord, Option<&AfterImage>)> = Vec::new();
+        for record in undo.records() {
+            match positions.entry((record.c_id, &record.key)) {
+                std::collections::hash_map::Entry::Occupied(e) => {
+                    let (_, after) = &mut images[*e.get()];
+                    *after = record.after.as_ref().or(*after);
+                }
+                std::collections::hash_map::Entry::Vacant(e) => {
+                    e.insert(images.len());
+                    images.push((record, record.after.as_ref()));
+                }
+            }
+        }
+        let now = Instant::now();
+        images
+            .into_iter()
+            .filter_map(|(first, after)| {
+                let old = visible_value(&first.before, now);
+                let new = visible_value(after?, now);
+                (old != new).then(|| (first.c_id, first.key.clone(), old, new))
+            })
+            .collect()
+    }
+
+    // Restores the keys of the undo records, which are ordered latest write first.
+    fn undo(&self, records: Vec<UndoRecord>) {
+        let containers = unsafe { &*self.containers.get() };
+        for record in records {
+            let storage = containers[record.c_id as usize].as_ref();
+            match record.before {
+                None => {
+                    let _ = storage.remove(&record.key);
+                }
+                Some((val, expire_at)) => storage.put(record.key, val, expire_at),
+            }
+        }
+    }
+
+    // Marks the transaction as committed or aborted and removes it from the active ones.
+    fn finish_txn(&self, txn: &InMemDummyTxnHandle, state: TxnState) -> Result<(), StorageError> {
+        let mut current = txn.shared.state.lock().unwrap();
+        if !matches!(*current, TxnState::Active | TxnState::Prepared) {
+            return Err(StorageError::new(Status::TxNotFound).with_txn(txn.txn_id()));
+        }
+        *current = state;
+        if state == TxnState::Committed {
+            let _ = txn.shared.commit_time.set(Instant::now());
         }
+        self.txns.lock().unwrap().remove(&txn.txn_id());
+        self.prepared.lock().unwrap().remove(&txn.txn_id());
+        Ok(())
     }
 }
 
-pub struct InMemDummyTxnHandle {
+// State of a transaction shared by its handle and the list of active transactions.
+struct TxnShared {
+    id: TxnId,
+    begin_time: Instant,
+    commit_time: OnceLock<Instant>,
+    state: Mutex<TxnState>,
+    writes: AtomicUsize, // number of keys written
     db_id: DatabaseId,
+    undo: Mutex<UndoLog>,
+}
+
+pub struct InMemDummyTxnHandle {
+    two_phase: bool,
+    shared: Arc<TxnShared>,
+    snapshot: Option<Vec<Arc<SnapshotPin>>>, // containers read by a read-only transaction
 }
 
 impl InMemDummyTxnHandle {
-    pub fn new(db_id: DatabaseId) -> Self {
-        InMemDummyTxnHandle { db_id }
+    fn new(db_id: DatabaseId, txn_id: TxnId, two_phase: bool) -> Self {
+        let shared = TxnShared {
+            id: txn_id,
+            begin_time: Instant::now(),
+            commit_time: OnceLock::new(),
+            state: Mutex::new(TxnState::Active),
+            writes: AtomicUsize::new(0),
+            db_id,
+            undo: Mutex::new(UndoLog::default()),
+        };
+        InMemDummyTxnHandle {
+            two_phase,
+            shared: Arc::new(shared),
+            snapshot: None,
+        }
     }
 
     pub fn db_id(&self) -> DatabaseId {
-        self.db_id
+        self.shared.db_id
+    }
+
+    pub fn txn_id(&self) -> TxnId {
+        self.shared.id
+    }
+
+    pub fn begin_time(&self) -> Instant {
+        self.shared.begin_time
+    }
+
+    /// None unless the transaction is committed.
+    pub fn commit_time(&self) -> Option<Instant> {
+        self.shared.commit_time.get().copied()
+    }
+
+    pub fn state(&self) -> TxnState {
+        *self.shared.state.lock().unwrap()
+    }
+
+    // Only active transactions can write. Prepared ones must stay able to abort, and
+    // snapshots are read-only.
+    fn check_active(&self) -> Result<(), StorageError> {
+        if self.snapshot.is_some() {
+            return Err(StorageError::new(Status::Error)
+                .with_txn(self.txn_id())
+                .with_source("the transaction is a read-only snapshot"));
+        }
+        match self.state() {
+            TxnState::Active => Ok(()),
+            TxnState::Prepared => Err(StorageError::new(Status::Error)
+                .with_txn(self.txn_id())
+                .with_source("the transaction is prepared")),
+            TxnState::Committed | TxnState::Aborted => {
+                Err(StorageError::new(Status::TxNotFound).with_txn(self.txn_id()))
+            }
+        }
+    }
+
+    // The pinned container if this is a snapshot. Containers created after the snapshot
+    // began are not found.
+    fn snapshot_pin(&self, c_id: &ContainerId) -> Option<Result<&Arc<SnapshotPin>, StorageError>> {
+        let pins = self.snapshot.as_ref()?;
+        let Some(pin) = pins.get(*c_id as usize) else {
+            return Some(Err(self.container_error(c_id)(Status::ContainerNotFound)));
+        };
+        if !pin.is_readable() {
+            return Some(Err(self.container_error(c_id)(Status::Error)
+                .with_source("the versions at the read time are not retained")));
+        }
+        Some(Ok(pin))
+    }
+
+    // Counts a write and records the state of the key before it, and after it if the
+    // container tracks its changes.
+    fn record(
+        &self,
+        c_id: &ContainerId,
+        key: Vec<u8>,
+        before: BeforeImage,
+        after: Option<AfterImage>,
+    ) {
+        self.shared.writes.fetch_add(1, Ordering::Relaxed);
+        self.shared
+            .undo
+            .lock()
+            .unwrap()
+            .record(*c_id, key, before, after);
+    }
+
+    // Adds the transaction, the container and the key to the status of a failed key
+    // operation.
+    fn key_error<'a>(
+        &self,
+        c_id: &ContainerId,
+        key: &'a [u8],
+    ) -> impl FnOnce(Status) -> StorageError + 'a {
+        let (txn_id, c_id) = (self.txn_id(), *c_id);
+        move |status| {
+            StorageError::from(status)
+                .with_txn(txn_id)
+                .with_container(c_id)
+                .with_key(key)
+        }
+    }
+
+    // Inserts and merges move the key into the container, so their errors only carry the
+    // transaction and the container.
+    fn container_error(&self, c_id: &ContainerId) -> impl FnOnce(Status) -> StorageError {
+        let (txn_id, c_id) = (self.txn_id(), *c_id);
+        move |status| {
+            StorageError::from(status)
+                .with_txn(txn_id)
+                .with_container(c_id)
+        }
     }
 }
 
@@ -296,28 +1780,30 @@ impl TxnStorageTrait for InMemStorage {
     type IteratorHandle = InMemIterator;
 
     // Open connection with the db
-    fn open_db(&self, _options: DBOptions) -> Result<DatabaseId, Status> {
+    fn open_db(&self, _options: DBOptions) -> Result<DatabaseId, StorageError> {
         let guard = unsafe { &mut *self.db_created.get() };
         if *guard {
-            return Err(Status::DBExists);
+            return Err(Status::DBExists.into());
         }
         *guard = true;
         Ok(0)
     }
 
     // Close connection with the db
-    fn close_db(&self, _db_id: &DatabaseId) -> Result<(), Status> {
+    fn close_db(&self, _db_id: &DatabaseId) -> Result<(), StorageError> {
         // Do nothing
         Ok(())
     }
 
     // Delete the db
-    fn delete_db(&self, db_id: &DatabaseId) -> Result<(), Status> {
+    fn delete_db(&self, db_id: &DatabaseId) -> Result<(), StorageError> {
         if *db_id != 0 {
-            return Err(Status::DBNotFound);
+            return Err(Status::DBNotFound.into());
         }
-        let guard = unsafe { &mut *self.db_created.get() };
-        *guard = false;
+        // The sweeper walks the containers under the read lock
+        let _guard = self.container_lock.write().unwrap();
+        let db_created = unsafe { &mut *self.db_created.get() };
+        *db_created = false;
         // Clear all the containers
         let containers = unsafe { &mut *self.containers.get() };
         containers.clear();
@@ -330,13 +1816,20 @@ impl TxnStorageTrait for InMemStorage {
         _txn: &Self::TxnHandle,
         db_id: &DatabaseId,
         options: ContainerOptions,
-    ) -> Result<ContainerId, Status> {
+    ) -> Result<ContainerId, StorageError> {
         if *db_id != 0 {
-            return Err(Status::DBNotFound);
+            return Err(Status::DBNotFound.into());
+        }
+        // Only Hash containers can be sharded. Ordered containers need a single shard
+        // to keep the keys in order, and ConcurrentHash containers lock each bucket.
+        match (options.get_type(), options.shards()) {
+            (_, 0) => return Err(Status::Error.into()),
+            (ContainerType::Hash, _) | (_, 1) => {}
+            _ => return Err(Status::Error.into()),
         }
         let _guard = self.container_lock.write().unwrap();
         let containers = unsafe { &mut *self.containers.get() };
-        let storage = Arc::new(Storage::new(options.get_type()));
+        let storage = Arc::new(Storage::new(&options, self.latch_mode));
         containers.push(storage);
         Ok((containers.len() - 1) as ContainerId)
     }
@@ -350,24 +1843,47 @@ impl TxnStorageTrait for InMemStorage {
         _txn: &Self::TxnHandle,
         db_id: &DatabaseId,
         c_id: &ContainerId,
-    ) -> Result<(), Status> {
+    ) -> Result<(), StorageError> {
         if *db_id != 0 {
-            return Err(Status::DBNotFound);
+            return Err(Status::DBNotFound.into());
         }
         let _guard = self.container_lock.write().unwrap();
         let containers = unsafe { &mut *self.containers.get() };
-        containers[*c_id as usize].clear();
+        containers[*c_id as usize].clear(false);
         Ok(())
     }
 
+    // Create a secondary index on a container
+    fn create_index(
+        &self,
+        txn: &Self::TxnHandle,
+        db_id: &DatabaseId,
+        c_id: &ContainerId,
+        options: IndexOptions,
+    ) -> Result<ContainerId, StorageError> {
+        let idx_id = self.create_container(
+            txn,
+            db_id,
+            ContainerOptions::new(options.name(), ContainerType::BTree),
+        )?;
+        let _guard = self.container_lock.read().unwrap();
+        let containers = unsafe { &*self.containers.get() };
+        let index = SecondaryIndex::new(
+            Arc::clone(&containers[idx_id as usize]),
+            options.key_extractor(),
+        );
+        containers[*c_id as usize].add_index(index);
+        Ok(idx_id)
+    }
+
     // List all container names in the db
     fn list_containers(
         &self,
         _txn: &Self::TxnHandle,
         db_id: &DatabaseId,
-    ) -> Result<HashSet<ContainerId>, Status> {
+    ) -> Result<HashSet<ContainerId>, StorageError> {
         if *db_id != 0 {
-            return Err(Status::DBNotFound);
+            return Err(Status::DBNotFound.into());
         }
         let _guard = self.container_lock.read().unwrap();
         let containers = unsafe { &mut *self.containers.get() };
@@ -378,94 +1894,286 @@ impl TxnStorageTrait for InMemStorage {
     fn begin_txn(
         &self,
         db_id: &DatabaseId,
-        _options: TxnOptions,
-    ) -> Result<Self::TxnHandle, Status> {
-        Ok(InMemDummyTxnHandle::new(*db_id))
+        options: TxnOptions,
+    ) -> Result<Self::TxnHandle, StorageError> {
+        let txn_id = self.next_txn_id.fetch_add(1, Ordering::Relaxed);
+        let mut txn = InMemDummyTxnHandle::new(*db_id, txn_id, options.two_phase_commit());
+        if let Some(time) = options.read_time() {
+            let _guard = self.container_lock.read().unwrap();
+            let containers = unsafe { &*self.containers.get() };
+            let pins = containers
+                .iter()
+                .map(|storage| {
+                    Arc::new(SnapshotPin {
+                        storage: Arc::clone(storage),
+                        at: ReadPoint::Time(time),
+                    })
+                })
+                .collect();
+            txn.snapshot = Some(pins);
+        }
+        self.txns
+            .lock()
+            .unwrap()
+            .insert(txn_id, Arc::downgrade(&txn.shared));
+        Ok(txn)
+    }
+
+    // Begin a snapshot. Writes are applied in place, so the snapshot also sees the writes
+    // of the transactions that were active when it began. While it is alive, the writes
+    // record their before-images and wait for the snapshot reads of the stripe of the
+    // version log of their key, which are bounded by SNAPSHOT_SCAN_BATCH keys for scans.
+    fn begin_snapshot(&self, db_id: &DatabaseId) -> Result<Self::TxnHandle, StorageError> {
+        let _guard = self.container_lock.read().unwrap();
+        let containers = unsafe { &*self.containers.get() };
+        // Lock all the stripes of the version logs of all the containers so that the
+        // snapshot begins at the same point in all of them. They are locked in container id
+        // and stripe order. A write locks the stripe of its key in an indexed container and
+        // then in its indexes, which have higher ids, and clearing a container locks all
+        // its stripes in order, so the locks are always taken in the same order.
+        let mut logs: Vec<Vec<_>> = containers
+            .iter()
+            .map(|storage| {
+                storage
+                    .versions
+                    .iter()
+                    .map(|log| log.write().unwrap())
+                    .collect()
+            })
+            .collect();
+        let pins = containers
+            .iter()
+            .zip(logs.iter_mut())
+            .map(|(storage, stripes)| {
+                let epochs: Vec<_> = stripes.iter_mut().map(|log| log.pin()).collect();
+                debug_assert!(epochs.windows(2).all(|w| w[0] == w[1]));
+                Arc::new(SnapshotPin {
+                    storage: Arc::clone(storage),
+                    at: ReadPoint::Epoch(epochs[0]),
+                })
+            })
+            .collect();
+        drop(logs);
+        let mut txn = self.begin_txn(db_id, TxnOptions::default())?;
+        txn.snapshot = Some(pins);
+        Ok(txn)
+    }
+
+    // Commit a transaction. Writes are applied in place, so committing only records the
+    // outcome, publishes the changes to the containers that capture them and notifies the
+    // watches.
+    // Commits only go through the change log, which orders them, if they have changes to
+    // publish.
+    fn commit_txn(&self, txn: &Self::TxnHandle, _async_commit: bool) -> Result<(), StorageError> {
+        let containers = unsafe { &*self.containers.get() };
+        let captured = |c_id: &ContainerId| containers[*c_id as usize].captures_changes;
+        let has_captured = txn
+            .shared
+            .undo
+            .lock()
+            .unwrap()
+            .records()
+            .iter()
+            .any(|record| captured(&record.c_id));
+        let mut changes = Vec::new();
+        if has_captured {
+            self.changes.commit(txn.txn_id(), || {
+                self.finish_txn(txn, TxnState::Committed)?;
+                changes = self.committed_changes(txn);
+                Ok(changes
+                    .iter()
+                    .filter(|(c_id, ..)| captured(c_id))
+                    .cloned()
+                    .collect())
+            })?;
+        } else {
+            self.finish_txn(txn, TxnState::Committed)?;
+            changes = self.committed_changes(txn);
+        }
+        for (c_id, key, _, new) in changes {
+            containers[c_id as usize]
+                .watchers
+                .notify(c_id, txn.txn_id(), key, new);
+        }
+        Ok(())
+    }
+
+    // Abort a transaction. Writes are applied in place, so they are undone by restoring
+    // the recorded keys, latest write first, and were visible to other transactions
+    // meanwhile. The changes recorded for the change log and the watches are dropped
+    // unpublished.
+    fn abort_txn(&self, txn: &Self::TxnHandle) -> Result<(), StorageError> {
+        self.finish_txn(txn, TxnState::Aborted)?;
+        let records = txn.shared.undo.lock().unwrap().take_records();
+        self.undo(records);
+        Ok(())
     }
 
-    // Commit a transaction
-    fn commit_txn(&self, _txn: &Self::TxnHandle, _async_commit: bool) -> Result<(), Status> {
+    // Prepare a transaction begun with TxnOptions::with_two_phase_commit(). The storage
+    // keeps the transaction until it is committed or aborted, also through a handle from
+    // prepared_txn(). It is not durable, so prepared transactions do not survive a restart.
+    fn prepare_txn(&self, txn: &Self::TxnHandle) -> Result<(), StorageError> {
+        if !txn.two_phase {
+            return Err(StorageError::new(Status::Error)
+                .with_txn(txn.txn_id())
+                .with_source("the transaction was not begun for two-phase commit"));
+        }
+        let mut state = txn.shared.state.lock().unwrap();
+        if *state != TxnState::Active {
+            return Err(StorageError::new(Status::TxNotFound).with_txn(txn.txn_id()));
+        }
+        *state = TxnState::Prepared;
+        self.prepared
+            .lock()
+            .unwrap()
+            .insert(txn.txn_id(), Arc::clone(&txn.shared));
         Ok(())
     }
 
-    // Abort a transaction
-    fn abort_txn(&self, _txn: &Self::TxnHandle) -> Result<(), Status> {
+    // Create a savepoint. Deleting containers is not recorded, so it is not rolled back.
+    fn savepoint(&self, txn: &Self::TxnHandle) -> Result<SavepointId, StorageError> {
+        txn.check_active()?;
+        Ok(txn.shared.undo.lock().unwrap().savepoint())
+    }
+
+    // Roll back to a savepoint by restoring the recorded keys, latest write first. The
+    // writes are undone in place, so they were visible to other transactions meanwhile.
+    fn rollback_to(
+        &self,
+        txn: &Self::TxnHandle,
+        savepoint: SavepointId,
+    ) -> Result<(), StorageError> {
+        txn.check_active()?;
+        let records = txn
+            .shared
+            .undo
+            .lock()
+            .unwrap()
+            .rollback_to(savepoint)
+            .map_err(|status| StorageError::new(status).with_txn(txn.txn_id()))?;
+        self.undo(records);
         Ok(())
     }
 
     // Wait for a transaction to finish
-    fn wait_for_txn(&self, _txn: &Self::TxnHandle) -> Result<(), Status> {
+    fn wait_for_txn(&self, _txn: &Self::TxnHandle) -> Result<(), StorageError> {
         Ok(())
     }
 
-    // Drop a transaction handle
-    fn drop_txn(&self, _txn: Self::TxnHandle) -> Result<(), Status> {
+    // Drop a transaction handle. A prepared transaction stays listed as active until it
+    // is committed or aborted.
+    fn drop_txn(&self, txn: Self::TxnHandle) -> Result<(), StorageError> {
+        if txn.state() != TxnState::Prepared {
+            self.txns.lock().unwrap().remove(&txn.txn_id());
+        }
         Ok(())
     }
 
     // Check if value exists
     fn check_value<K: AsRef<[u8]>>(
         &self,
-        _txn: &Self::TxnHandle,
+        txn: &Self::TxnHandle,
         c_id: &ContainerId,
         key: K,
-    ) -> Result<bool, Status> {
+    ) -> Result<bool, StorageError> {
+        if let Some(pin) = txn.snapshot_pin(c_id) {
+            return Ok(pin?.get(key.as_ref()).is_ok());
+        }
         // Access the container with the container_id. No guard
         // is required because we assume that container is
         // already created.
         let containers = unsafe { &*self.containers.get() };
         let storage = containers[*c_id as usize].as_ref();
-        match storage.get(key.as_ref()) {
-            Ok(_) => Ok(true),
-            Err(_) => Ok(false),
-        }
+        Ok(storage.get(key.as_ref()).is_ok())
     }
 
     // Get value
     fn get_value<K: AsRef<[u8]>>(
         &self,
-        _txn: &Self::TxnHandle,
+        txn: &Self::TxnHandle,
         c_id: &ContainerId,
         key: K,
-    ) -> Result<Vec<u8>, Status> {
+    ) -> Result<Vec<u8>, StorageError> {
+        if let Some(pin) = txn.snapshot_pin(c_id) {
+            return pin?
+                .get(key.as_ref())
+                .map_err(txn.key_error(c_id, key.as_ref()));
+        }
         // Access the container with the container_id. No guard
         // is required because we assume that container is
         // already created.
         let containers = unsafe { &*self.containers.get() };
         let storage = containers[*c_id as usize].as_ref();
-        storage.get(key.as_ref())
+        storage
+            .get(key.as_ref())
+            .map_err(txn.key_error(c_id, key.as_ref()))
     }
 
     // Insert value
     fn insert_value(
         &self,
-        _txn: &Self::TxnHandle,
+        txn: &Self::TxnHandle,
         c_id: &ContainerId,
         key: Vec<u8>,
         value: Vec<u8>,
-    ) -> Result<(), Status> {
+    ) -> Result<(), StorageError> {
+        txn.check_active()?;
+        // Access the container with the container_id. No guard
+        // is required because we assume that container is
+        // already created.
+        let containers = unsafe { &*self.containers.get() };
+        let storage = containers[*c_id as usize].as_ref();
+        let after = storage.after_image(|| Some((value.clone(), None)));
+        storage
+            .insert(key.clone(), value, None)
+            .map_err(txn.container_error(c_id))?;
+        txn.record(c_id, key, None, after);
+        Ok(())
+    }
+
+    // Insert value that expires after ttl
+    fn insert_value_with_ttl(
+        &self,
+        txn: &Self::TxnHandle,
+        c_id: &ContainerId,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), StorageError> {
+        txn.check_active()?;
         // Access the container with the container_id. No guard
         // is required because we assume that container is
         // already created.
         let containers = unsafe { &*self.containers.get() };
         let storage = containers[*c_id as usize].as_ref();
-        storage.insert(key, value)
+        let expire_at = Some(Instant::now() + ttl);
+        let after = storage.after_image(|| Some((value.clone(), expire_at)));
+        storage
+            .insert(key.clone(), value, expire_at)
+            .map_err(txn.container_error(c_id))?;
+        txn.record(c_id, key, None, after);
+        Ok(())
     }
 
     // Insert values
     fn insert_values(
         &self,
-        _txn: &Self::TxnHandle,
+        txn: &Self::TxnHandle,
         c_id: &ContainerId,
         kvs: Vec<(Vec<u8>, Vec<u8>)>,
-    ) -> Result<(), Status> {
+    ) -> Result<(), StorageError> {
+        txn.check_active()?;
         // Access the container with the container_id. No guard
         // is required because we assume that container is
         // already created.
         let containers = unsafe { &*self.containers.get() };
         let storage = containers[*c_id as usize].as_ref();
         for (k, v) in kvs {
-            storage.insert(k, v)?;
+            let after = storage.after_image(|| Some((v.clone(), None)));
+            storage
+                .insert(k.clone(), v, None)
+                .map_err(txn.container_error(c_id))?;
+            txn.record(c_id, k, None, after);
         }
         Ok(())
     }
@@ -473,44 +2181,189 @@ impl TxnStorageTrait for InMemStorage {
     // Update value
     fn update_value<K>(
         &self,
-        _txn: &Self::TxnHandle,
+        txn: &Self::TxnHandle,
         c_id: &ContainerId,
         key: K,
         value: Vec<u8>,
-    ) -> Result<(), Status>
+    ) -> Result<(), StorageError>
     where
         K: AsRef<[u8]>,
     {
+        txn.check_active()?;
+        // Access the container with the container_id. No guard
+        // is required because we assume that container is
+        // already created.
+        let containers = unsafe { &*self.containers.get() };
+        let storage = containers[*c_id as usize].as_ref();
+        let after = storage.after_image(|| Some((value.clone(), None)));
+        let old = storage
+            .update(key.as_ref(), value, None)
+            .map_err(txn.key_error(c_id, key.as_ref()))?;
+        txn.record(
+            c_id,
+            key.as_ref().to_vec(),
+            storage.before_image(&old),
+            after,
+        );
+        Ok(())
+    }
+
+    // Update value and make it expire after ttl
+    fn update_value_with_ttl<K: AsRef<[u8]>>(
+        &self,
+        txn: &Self::TxnHandle,
+        c_id: &ContainerId,
+        key: K,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), StorageError> {
+        txn.check_active()?;
+        // Access the container with the container_id. No guard
+        // is required because we assume that container is
+        // already created.
+        let containers = unsafe { &*self.containers.get() };
+        let storage = containers[*c_id as usize].as_ref();
+        let expire_at = Some(Instant::now() + ttl);
+        let after = storage.after_image(|| Some((value.clone(), expire_at)));
+        let old = storage
+            .update(key.as_ref(), value, expire_at)
+            .map_err(txn.key_error(c_id, key.as_ref()))?;
+        txn.record(
+            c_id,
+            key.as_ref().to_vec(),
+            storage.before_image(&old),
+            after,
+        );
+        Ok(())
+    }
+
+    // Merge operand into value
+    fn merge_value(
+        &self,
+        txn: &Self::TxnHandle,
+        c_id: &ContainerId,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+    ) -> Result<(), StorageError> {
+        txn.check_active()?;
         // Access the container with the container_id. No guard
         // is required because we assume that container is
         // already created.
         let containers = unsafe { &*self.containers.get() };
         let storage = containers[*c_id as usize].as_ref();
-        storage.update(key.as_ref(), value)
+        let before = storage.get_with_expiry(&key).ok();
+        let image = storage.tracks_changes();
+        let after = storage
+            .merge(key.clone(), operand, image)
+            .map_err(txn.container_error(c_id))?;
+        txn.record(c_id, key, before, image.then_some(after));
+        Ok(())
+    }
+
+    // Get the primary keys whose secondary key is sec_key
+    fn lookup_index<K: AsRef<[u8]>>(
+        &self,
+        txn: &Self::TxnHandle,
+        idx_id: &ContainerId,
+        sec_key: K,
+    ) -> Result<Vec<Vec<u8>>, StorageError> {
+        let prefix = encode_secondary_key(sec_key.as_ref());
+        if let Some(pin) = txn.snapshot_pin(idx_id) {
+            let iter = InMemIterator::snapshot(Arc::clone(pin?), Bound::Included(prefix.clone()));
+            return Ok(std::iter::from_fn(|| iter.next())
+                .take_while(|(k, _)| k.starts_with(&prefix))
+                .map(|(_, v)| v)
+                .collect());
+        }
+        // Access the container with the container_id. No guard
+        // is required because we assume that container is
+        // already created.
+        let containers = unsafe { &*self.containers.get() };
+        let storage = containers[*idx_id as usize].as_ref();
+        storage
+            .prefix_values(&prefix)
+            .map_err(txn.key_error(idx_id, sec_key.as_ref()))
     }
 
     // Delete value
     fn delete_value<K: AsRef<[u8]>>(
         &self,
-        _txn: &Self::TxnHandle,
+        txn: &Self::TxnHandle,
         c_id: &ContainerId,
         key: K,
-    ) -> Result<(), Status> {
+    ) -> Result<(), StorageError> {
+        txn.check_active()?;
+        // Access the container with the container_id. No guard
+        // is required because we assume that container is
+        // already created.
+        let containers = unsafe { &*self.containers.get() };
+        let storage = containers[*c_id as usize].as_ref();
+        let old = storage
+            .remove(key.as_ref())
+            .map_err(txn.key_error(c_id, key.as_ref()))?;
+        let after = storage.after_image(|| None);
+        txn.record(
+            c_id,
+            key.as_ref().to_vec(),
+            storage.before_image(&old),
+            after,
+        );
+        Ok(())
+    }
+
+    // Delete all the keys in [start, end)
+    fn delete_range<K: AsRef<[u8]>>(
+        &self,
+        txn: &Self::TxnHandle,
+        c_id: &ContainerId,
+        start: K,
+        end: K,
+    ) -> Result<(), StorageError> {
+        txn.check_active()?;
+        // Access the container with the container_id. No guard
+        // is required because we assume that container is
+        // already created.
+        let containers = unsafe { &*self.containers.get() };
+        let storage = containers[*c_id as usize].as_ref();
+        let removed = storage
+            .remove_range(start.as_ref(), end.as_ref())
+            .map_err(txn.key_error(c_id, start.as_ref()))?;
+        let after = storage.after_image(|| None);
+        for (key, entry) in removed.into_iter().filter(|(_, e)| !e.is_expired()) {
+            txn.record(c_id, key, storage.before_image(&entry), after.clone());
+        }
+        Ok(())
+    }
+
+    // Delete all the keys in the container
+    fn truncate_container(
+        &self,
+        txn: &Self::TxnHandle,
+        c_id: &ContainerId,
+    ) -> Result<(), StorageError> {
+        txn.check_active()?;
         // Access the container with the container_id. No guard
         // is required because we assume that container is
         // already created.
         let containers = unsafe { &*self.containers.get() };
         let storage = containers[*c_id as usize].as_ref();
-        storage.remove(key.as_ref())
+        let after = storage.after_image(|| None);
+        for (key, before) in storage.clear(true) {
+            txn.record(c_id, key, before, after.clone());
+        }
+        Ok(())
     }
 
     // Scan range
     fn scan_range(
         &self,
-        _txn: &Self::TxnHandle,
+        txn: &Self::TxnHandle,
         c_id: &ContainerId,
         _options: ScanOptions,
-    ) -> Result<Self::IteratorHandle, Status> {
+    ) -> Result<Self::IteratorHandle, StorageError> {
+        if let Some(pin) = txn.snapshot_pin(c_id) {
+            return Ok(InMemIterator::snapshot(Arc::clone(pin?), Bound::Unbounded));
+        }
         // Access the container with the container_id. No guard
         // is required because we assume that container is
         // already created.
@@ -519,13 +2372,127 @@ impl TxnStorageTrait for InMemStorage {
     }
 
     // Iterate next
-    fn iter_next(&self, iter: &Self::IteratorHandle) -> Result<Option<(Vec<u8>, Vec<u8>)>, Status> {
+    fn iter_next(
+        &self,
+        iter: &Self::IteratorHandle,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, StorageError> {
         Ok(iter.next())
     }
 
     // Drop an iterator handle
-    fn drop_iterator_handle(&self, _iter: Self::IteratorHandle) -> Result<(), Status> {
+    fn drop_iterator_handle(&self, _iter: Self::IteratorHandle) -> Result<(), StorageError> {
         // Do nothing
         Ok(())
     }
 }
+
+// Run with: RUSTFLAGS="--cfg loom" cargo test --release loom
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::thread;
+
+    fn storage(c_type: ContainerType) -> Arc<Storage> {
+        let options = ContainerOptions::new("loom", c_type);
+        Arc::new(Storage::new(&options, LatchMode::Spin))
+    }
+
+    fn model<F: Fn() + Sync + Send + 'static>(f: F) {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(2);
+        builder.check(f);
+    }
+
+    #[test]
+    fn loom_insert_and_get() {
+        for c_type in [ContainerType::Hash, ContainerType::BTree] {
+            model(move || {
+                let storage = storage(c_type.clone());
+                let inserter = {
+                    let storage = storage.clone();
+                    thread::spawn(move || storage.insert(b"k".to_vec(), b"v".to_vec(), None))
+                };
+                match storage.get(b"k") {
+                    Ok(val) => assert_eq!(val, b"v"),
+                    Err(status) => assert_eq!(status, Status::KeyNotFound),
+                }
+                inserter.join().unwrap().unwrap();
+                assert_eq!(storage.get(b"k").unwrap(), b"v");
+            });
+        }
+    }
+
+    #[test]
+    fn loom_remove_and_get() {
+        for c_type in [ContainerType::Hash, ContainerType::BTree] {
+            model(move || {
+                let storage = storage(c_type.clone());
+                storage.insert(b"k".to_vec(), b"v".to_vec(), None).unwrap();
+                let remover = {
+                    let storage = storage.clone();
+                    thread::spawn(move || storage.remove(b"k"))
+                };
+                match storage.get(b"k") {
+                    Ok(val) => assert_eq!(val, b"v"),
+                    Err(status) => assert_eq!(status, Status::KeyNotFound),
+                }
+                remover.join().unwrap().unwrap();
+                assert_eq!(storage.get(b"k"), Err(Status::KeyNotFound));
+            });
+        }
+    }
+
+    #[test]
+    fn loom_insert_and_iter() {
+        model(|| {
+            let storage = storage(ContainerType::BTree);
+            storage.insert(b"a".to_vec(), b"1".to_vec(), None).unwrap();
+            let inserter = {
+                let storage = storage.clone();
+                thread::spawn(move || storage.insert(b"b".to_vec(), b"2".to_vec(), None))
+            };
+            // The iterator holds the latch, so it sees either both keys or only the first
+            let iter = storage.iter();
+            let mut keys = Vec::new();
+            while let Some((key, _)) = iter.next() {
+                keys.push(key);
+            }
+            drop(iter);
+            assert!(keys == [b"a".to_vec()] || keys == [b"a".to_vec(), b"b".to_vec()]);
+            inserter.join().unwrap().unwrap();
+        });
+    }
+
+    #[test]
+    fn loom_abort_and_commit() {
+        model(|| {
+            let storage = Arc::new(InMemStorage::new());
+            let db_id = storage.open_db(DBOptions::new("loom")).unwrap();
+            let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+            let options = ContainerOptions::new("loom", ContainerType::BTree);
+            let c_id = storage.create_container(&txn, &db_id, options).unwrap();
+            storage.commit_txn(&txn, false).unwrap();
+            // The aborted writes are undone without touching the committed ones
+            let aborter = {
+                let storage = storage.clone();
+                thread::spawn(move || {
+                    let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+                    storage
+                        .insert_value(&txn, &c_id, b"a".to_vec(), b"1".to_vec())
+                        .unwrap();
+                    storage.abort_txn(&txn).unwrap();
+                })
+            };
+            let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+            storage
+                .insert_value(&txn, &c_id, b"b".to_vec(), b"2".to_vec())
+                .unwrap();
+            storage.commit_txn(&txn, false).unwrap();
+            aborter.join().unwrap();
+            let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+            assert_eq!(storage.check_value(&txn, &c_id, b"a"), Ok(false));
+            assert_eq!(storage.check_value(&txn, &c_id, b"b"), Ok(true));
+            storage.commit_txn(&txn, false).unwrap();
+        });
+    }
+}
@@ -0,0 +1,264 @@
+use std::{collections::BTreeMap, ops::Bound, sync::Mutex, time::Instant};
+
+use crossbeam_skiplist::SkipMap;
+
+use super::{ttl::ExpiryQueue, AfterImage, Entries, Entry, EntryStore};
+use crate::prelude::*;
+
+/// Ordered container without a container-wide latch. The keys are kept in a lock-free
+/// skiplist and each key has its own mutex, so writers to different keys do not block
+/// each other and scans do not block writers.
+///
+/// The mutex of a key holds None until the first write to a newly created node. A key is
+/// removed by taking the entry and unlinking the node while holding its mutex. A writer
+/// that finds the node unlinked after locking it retries with a fresh node, so writes
+/// are never lost in an unlinked node.
+pub struct SkipList {
+    map: SkipMap<Vec<u8>, Mutex<Option<Entry>>>,
+    expiries: Mutex<ExpiryQueue>,
+    merge_operator: Option<MergeOperator>,
+}
+
+impl SkipList {
+    pub fn new(merge_operator: Option<MergeOperator>) -> Self {
+        SkipList {
+            map: SkipMap::new(),
+            expiries: Mutex::new(ExpiryQueue::default()),
+            merge_operator,
+        }
+    }
+
+    fn schedule_expiry(&self, key: &[u8], expire_at: Option<Instant>) {
+        if let Some(expire_at) = expire_at {
+            self.expiries.lock().unwrap().push(expire_at, key.to_vec());
+        }
+    }
+
+    // Locks the node of the key, creating it if needed, and calls f with its entry.
+    fn with_node<T>(&self, key: Vec<u8>, f: impl FnOnce(&mut Option<Entry>) -> T) -> T {
+        loop {
+            let node = self
+                .map
+                .get_or_insert_with(key.clone(), || Mutex::new(None));
+            let mut slot = node.value().lock().unwrap();
+            if !node.is_removed() {
+                return f(&mut slot);
+            }
+        }
+    }
+
+    // Takes the entry of the key and unlinks its node.
+    fn take(&self, key: &[u8]) -> Option<Entry> {
+        let node = self.map.get(key)?;
+        let mut slot = node.value().lock().unwrap();
+        if node.is_removed() {
+            return None;
+        }
+        let entry = slot.take();
+        node.remove();
+        entry
+    }
+
+    /// The first non-expired key after `key` (or the first key if None) and its value.
+    pub fn next_after(&self, key: Option<&[u8]>) -> Option<(Vec<u8>, Vec<u8>)> {
+        let mut node = match key {
+            Some(key) => self.map.lower_bound(Bound::Excluded(key)),
+            None => self.map.front(),
+        };
+        while let Some(n) = node {
+            if let Some(entry) = n.value().lock().unwrap().as_ref() {
+                if !entry.is_expired() {
+                    return Some((n.key().clone(), entry.value(self.merge_operator.as_ref())));
+                }
+            }
+            node = n.next();
+        }
+        None
+    }
+}
+
+impl EntryStore for SkipList {
+    fn clear_entries(&self) {
+        for node in self.map.iter() {
+            self.take(node.key());
+        }
+        self.expiries.lock().unwrap().clear();
+    }
+
+    fn insert_entry(
+        &self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        expire_at: Option<Instant>,
+    ) -> Result<(), Status> {
+        let result = self.with_node(key.clone(), |slot| match slot {
+            Some(entry) if !entry.is_expired() => Err(Status::KeyExists),
+            _ => {
+                *slot = Some(Entry::new(val, expire_at));
+                Ok(())
+            }
+        });
+        if result.is_ok() {
+            self.schedule_expiry(&key, expire_at);
+        }
+        result
+    }
+
+    fn get_at(&self, key: &[u8], at: Instant) -> Result<(Vec<u8>, Option<Instant>), Status> {
+        let node = self.map.get(key).ok_or(Status::KeyNotFound)?;
+        let slot = node.value().lock().unwrap();
+        match slot.as_ref() {
+            Some(entry) if !entry.is_expired_at(at) => {
+                Ok((entry.value(self.merge_operator.as_ref()), entry.expire_at))
+            }
+            _ => Err(Status::KeyNotFound),
+        }
+    }
+
+    fn update_entry(
+        &self,
+        key: &[u8],
+        val: Vec<u8>,
+        expire_at: Option<Instant>,
+    ) -> Result<Entry, Status> {
+        let node = self.map.get(key).ok_or(Status::KeyNotFound)?;
+        let mut slot = node.value().lock().unwrap();
+        let result = match slot.as_mut() {
+            Some(entry) if !node.is_removed() && !entry.is_expired() => {
+                Ok(std::mem::replace(entry, Entry::new(val, expire_at)))
+            }
+            _ => Err(Status::KeyNotFound),
+        };
+        drop(slot);
+        if result.is_ok() {
+            self.schedule_expiry(key, expire_at);
+        }
+        result
+    }
+
+    fn merge_entry(
+        &self,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+        image: bool,
+    ) -> Result<AfterImage, Status> {
+        let merge_operator = self.merge_operator.as_ref().ok_or(Status::Error)?;
+        let after = self.with_node(key, |slot| {
+            let entry = match slot {
+                Some(entry) if !entry.is_expired() => {
+                    entry.merge(operand, merge_operator);
+                    entry
+                }
+                _ => slot.insert(Entry::operand(operand)),
+            };
+            image.then(|| (entry.value(Some(merge_operator)), entry.expire_at))
+        });
+        Ok(after)
+    }
+
+    fn remove_entry(&self, key: &[u8]) -> Result<Entry, Status> {
+        let node = self.map.get(key).ok_or(Status::KeyNotFound)?;
+        let mut slot = node.value().lock().unwrap();
+        match slot.take() {
+            Some(entry) if !node.is_removed() && !entry.is_expired() => {
+                node.remove();
+                Ok(entry)
+            }
+            entry => {
+                *slot = entry;
+                Err(Status::KeyNotFound)
+            }
+        }
+    }
+
+    // Removes the keys one by one. Keys inserted into the range concurrently may survive.
+    fn remove_range_entries(
+        &self,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<BTreeMap<Vec<u8>, Entry>, Status> {
+        let mut removed = BTreeMap::new();
+        if start >= end {
+            return Ok(removed);
+        }
+        for node in self
+            .map
+            .range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+        {
+            if let Some(entry) = self.take(node.key()) {
+                removed.insert(node.key().clone(), entry);
+            }
+        }
+        Ok(removed)
+    }
+
+    fn prefix_values(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Status> {
+        let mut values = Vec::new();
+        for node in self
+            .map
+            .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+        {
+            if !node.key().starts_with(prefix) {
+                break;
+            }
+            if let Some(entry) = node.value().lock().unwrap().as_ref() {
+                if !entry.is_expired() {
+                    values.push(entry.value(self.merge_operator.as_ref()));
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    fn entries_from(
+        &self,
+        start: Bound<&[u8]>,
+        limit: usize,
+        at: Instant,
+    ) -> Result<Entries, Status> {
+        let mut entries = Vec::new();
+        for node in self.map.range::<[u8], _>((start, Bound::Unbounded)) {
+            if entries.len() == limit {
+                break;
+            }
+            if let Some(entry) = node.value().lock().unwrap().as_ref() {
+                if !entry.is_expired_at(at) {
+                    let val = entry.value(self.merge_operator.as_ref());
+                    entries.push((node.key().clone(), val));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn for_each_entry(&self, at: Instant, f: &mut dyn FnMut(&[u8], &Entry)) {
+        for node in self.map.iter() {
+            if let Some(entry) = node.value().lock().unwrap().as_ref() {
+                if !entry.is_expired_at(at) {
+                    f(node.key(), entry);
+                }
+            }
+        }
+    }
+
+    fn remove_expired(&self, before: Instant) {
+        let mut keys = Vec::new();
+        {
+            let mut expiries = self.expiries.lock().unwrap();
+            while let Some(key) = expiries.pop_expired(before) {
+                keys.push(key);
+            }
+        }
+        for key in keys {
+            let Some(node) = self.map.get(&key) else {
+                continue;
+            };
+            let mut slot = node.value().lock().unwrap();
+            // The key might have been overwritten with a later expiry or no expiry
+            if !node.is_removed() && slot.as_ref().is_some_and(|e| e.is_expired_at(before)) {
+                slot.take();
+                node.remove();
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    sync::{Arc, Condvar, Mutex, Weak},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use super::InMemStorage;
+
+/// Keys ordered by their expiration time. Entries are not removed when the key is
+/// updated or deleted, so the popped key must be checked against the container.
+#[derive(Default)]
+pub struct ExpiryQueue {
+    heap: BinaryHeap<Reverse<(Instant, Vec<u8>)>>,
+}
+
+impl ExpiryQueue {
+    pub fn push(&mut self, expire_at: Instant, key: Vec<u8>) {
+        self.heap.push(Reverse((expire_at, key)));
+    }
+
+    /// Pop a key whose expiration time is not later than `now`.
+    pub fn pop_expired(&mut self, now: Instant) -> Option<Vec<u8>> {
+        match self.heap.peek() {
+            Some(Reverse((expire_at, _))) if *expire_at <= now => {
+                self.heap.pop().map(|Reverse((_, key))| key)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.heap.clear();
+    }
+}
+
+/// Background thread that periodically removes expired keys from the storage.
+/// The thread only holds a weak reference to the storage so that it does not keep
+/// the storage alive. Dropping the sweeper stops the thread.
+pub struct Sweeper {
+    shutdown: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    pub fn spawn(storage: Weak<InMemStorage>, interval: Duration) -> Self {
+        let shutdown = Arc::new((Mutex::new(false), Condvar::new()));
+        let handle = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || loop {
+                let (lock, cvar) = &*shutdown;
+                let stop = lock.lock().unwrap();
+                let (stop, _) = cvar.wait_timeout_while(stop, interval, |s| !*s).unwrap();
+                if *stop {
+                    break;
+                }
+                drop(stop);
+                match storage.upgrade() {
+                    Some(storage) => storage.sweep_expired(),
+                    None => break,
+                }
+            })
+        };
+        Sweeper {
+            shutdown,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.shutdown;
+        *lock.lock().unwrap() = true;
+        cvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            // The last reference to the storage can be dropped by the sweeper itself
+            // right after a sweep. It will exit on its own, so don't join.
+            if handle.thread().id() != thread::current().id() {
+                handle.join().unwrap();
+            }
+        }
+    }
+}
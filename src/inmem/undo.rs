@@ -0,0 +1,73 @@
+use std::time::Instant;
+
+use crate::prelude::*;
+
+// Value and expiry of a key before a write, or None if the key did not exist.
+pub type BeforeImage = Option<(Vec<u8>, Option<Instant>)>;
+// Value and expiry of a key after a write, or None if the write removed it.
+pub type AfterImage = Option<(Vec<u8>, Option<Instant>)>;
+
+pub struct UndoRecord {
+    pub c_id: ContainerId,
+    pub key: Vec<u8>,
+    pub before: BeforeImage,
+    // None unless the container tracked its changes when the key was written
+    pub after: Option<AfterImage>,
+}
+
+/// Before-images of all the writes of a transaction, used to roll back to a savepoint
+/// and to undo the transaction when it aborts, with the after-images of the writes to
+/// the containers that track their changes, which are published when it commits.
+#[derive(Default)]
+pub struct UndoLog {
+    records: Vec<UndoRecord>,
+    savepoints: Vec<usize>, // number of records when each savepoint was created
+}
+
+impl UndoLog {
+    pub fn record(
+        &mut self,
+        c_id: ContainerId,
+        key: Vec<u8>,
+        before: BeforeImage,
+        after: Option<AfterImage>,
+    ) {
+        self.records.push(UndoRecord {
+            c_id,
+            key,
+            before,
+            after,
+        });
+    }
+
+    // The records, earliest first.
+    pub fn records(&self) -> &[UndoRecord] {
+        &self.records
+    }
+
+    // Removes all the records and returns them, latest first.
+    pub fn take_records(&mut self) -> Vec<UndoRecord> {
+        self.savepoints.clear();
+        let mut records = std::mem::take(&mut self.records);
+        records.reverse();
+        records
+    }
+
+    pub fn savepoint(&mut self) -> SavepointId {
+        self.savepoints.push(self.records.len());
+        (self.savepoints.len() - 1) as SavepointId
+    }
+
+    // Removes the records after the savepoint and returns them, latest first.
+    // The savepoint stays valid and the savepoints created after it are released.
+    pub fn rollback_to(&mut self, savepoint: SavepointId) -> Result<Vec<UndoRecord>, Status> {
+        let len = *self
+            .savepoints
+            .get(savepoint as usize)
+            .ok_or(Status::Error)?;
+        self.savepoints.truncate(savepoint as usize + 1);
+        let mut undone = self.records.split_off(len);
+        undone.reverse();
+        Ok(undone)
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+    time::{Duration, Instant, SystemTime},
+};
+
+use super::undo::BeforeImage;
+
+/// What a read-only transaction reads the container as of: the epoch pinned by a
+/// snapshot, or a past time.
+#[derive(Clone, Copy)]
+pub enum ReadPoint {
+    Epoch(u64),
+    Time(SystemTime),
+}
+
+impl ReadPoint {
+    // The time that the expiries of the keys read are compared with. Snapshots read the
+    // keys that have not expired yet, like the other transactions.
+    pub fn instant(&self) -> Instant {
+        let now = Instant::now();
+        match self {
+            ReadPoint::Epoch(_) => now,
+            ReadPoint::Time(time) => SystemTime::now()
+                .duration_since(*time)
+                .ok()
+                .and_then(|ago| now.checked_sub(ago))
+                .unwrap_or(now),
+        }
+    }
+}
+
+struct Version {
+    epoch: u64,
+    written_at: SystemTime,
+    before: BeforeImage,
+}
+
+impl Version {
+    fn is_after(&self, at: &ReadPoint) -> bool {
+        match at {
+            ReadPoint::Epoch(epoch) => self.epoch > *epoch,
+            ReadPoint::Time(time) => self.written_at > *time,
+        }
+    }
+}
+
+/// Before-images of the keys of a container written while snapshots of it are alive, or
+/// within the retention window of a container that keeps its versions.
+///
+/// Each snapshot pins an epoch and writes are tagged with the epoch they happened in,
+/// which is later than the epochs of all the snapshots alive at that time. A read sees
+/// the before-image of the first write after its epoch or time, or the current value if
+/// the key was not written since. Writes are only recorded while they can be read, so
+/// containers without snapshots or retention pay nothing.
+///
+/// A container splits its keys over several logs, its stripes, each behind its own lock,
+/// so that writers to different stripes do not wait for each other. Every snapshot is
+/// pinned in all the stripes at once, so the stripes always have the same epoch.
+pub struct VersionLog {
+    epoch: u64,
+    retention: Option<Duration>,
+    snapshots: BTreeSet<u64>, // epochs of the live snapshots
+    versions: BTreeMap<Vec<u8>, Vec<Version>>, // in write order
+}
+
+impl VersionLog {
+    pub fn new(retention: Option<Duration>) -> Self {
+        VersionLog {
+            epoch: 0,
+            retention,
+            snapshots: BTreeSet::new(),
+            versions: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        !self.snapshots.is_empty() || self.retention.is_some()
+    }
+
+    // Whether the versions written after the time are all retained.
+    pub fn retains(&self, time: SystemTime) -> bool {
+        self.retention.is_some_and(|retention| {
+            SystemTime::now()
+                .checked_sub(retention)
+                .is_none_or(|since| time >= since)
+        })
+    }
+
+    pub fn pin(&mut self) -> u64 {
+        let epoch = self.epoch;
+        self.snapshots.insert(epoch);
+        self.epoch += 1;
+        epoch
+    }
+
+    pub fn unpin(&mut self, epoch: u64) {
+        self.snapshots.remove(&epoch);
+        self.prune();
+    }
+
+    // Releases the versions that can no longer be read.
+    pub fn prune(&mut self) {
+        let is_needed = self.is_needed();
+        self.versions.retain(|_, versions| {
+            versions.retain(&is_needed);
+            !versions.is_empty()
+        });
+    }
+
+    // Whether a version can be read by a live snapshot or within the retention window.
+    fn is_needed(&self) -> impl Fn(&Version) -> bool {
+        let oldest = self.snapshots.first().copied();
+        let since = self
+            .retention
+            .map(|retention| SystemTime::now().checked_sub(retention));
+        move |version| {
+            oldest.is_some_and(|epoch| version.epoch > epoch)
+                || since.is_some_and(|since| since.is_none_or(|t| version.written_at > t))
+        }
+    }
+
+    pub fn record(&mut self, key: &[u8], before: impl FnOnce() -> BeforeImage) {
+        if !self.is_recording() {
+            return;
+        }
+        let is_needed = self.is_needed();
+        let versions = self.versions.entry(key.to_vec()).or_default();
+        versions.retain(&is_needed);
+        // Snapshots only see the first write of an epoch, reads at a time see all of them
+        if self.retention.is_some() || versions.last().is_none_or(|v| v.epoch < self.epoch) {
+            versions.push(Version {
+                epoch: self.epoch,
+                written_at: SystemTime::now(),
+                before: before(),
+            });
+        }
+    }
+
+    // The before-image seen by a read at the point, or None if the key was not written
+    // since.
+    pub fn get(&self, key: &[u8], at: &ReadPoint) -> Option<&BeforeImage> {
+        self.versions
+            .get(key)?
+            .iter()
+            .find(|version| version.is_after(at))
+            .map(|version| &version.before)
+    }
+
+    // The keys in the range written since the point, with the before-images seen by a
+    // read at the point.
+    pub fn range<'a>(
+        &'a self,
+        range: (Bound<&[u8]>, Bound<&[u8]>),
+        at: &'a ReadPoint,
+    ) -> impl Iterator<Item = (&'a Vec<u8>, &'a BeforeImage)> {
+        self.versions
+            .range::<[u8], _>(range)
+            .filter_map(move |(key, _)| Some((key, self.get(key, at)?)))
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    fn image(val: u8) -> BeforeImage {
+        Some((vec![val], None))
+    }
+
+    #[test]
+    fn test_snapshots_see_first_write_after_them() {
+        let mut log = VersionLog::new(None);
+        log.record(b"a", || image(0)); // no snapshot, not recorded
+        let s1 = ReadPoint::Epoch(log.pin());
+        log.record(b"a", || image(1));
+        log.record(b"a", || image(2)); // same epoch, not recorded
+        let s2 = ReadPoint::Epoch(log.pin());
+        log.record(b"a", || image(3));
+        log.record(b"b", || None);
+        assert_eq!(log.get(b"a", &s1), Some(&image(1)));
+        assert_eq!(log.get(b"a", &s2), Some(&image(3)));
+        assert_eq!(log.get(b"b", &s1), Some(&None));
+        assert_eq!(log.get(b"c", &s1), None);
+        let keys: Vec<_> = log
+            .range((Bound::Excluded(&b"a"[..]), Bound::Unbounded), &s2)
+            .map(|(k, _)| k.clone())
+            .collect();
+        assert_eq!(keys, [b"b".to_vec()]);
+    }
+
+    #[test]
+    fn test_unpin_releases_versions() {
+        let mut log = VersionLog::new(None);
+        let s1 = log.pin();
+        log.record(b"a", || image(1));
+        let s2 = log.pin();
+        log.record(b"a", || image(2));
+        log.unpin(s1);
+        assert_eq!(log.versions[&b"a".to_vec()].len(), 1);
+        assert_eq!(log.get(b"a", &ReadPoint::Epoch(s2)), Some(&image(2)));
+        log.unpin(s2);
+        assert!(log.versions.is_empty());
+        assert!(!log.is_recording());
+    }
+
+    #[test]
+    fn test_retention() {
+        let mut log = VersionLog::new(Some(Duration::from_secs(3600)));
+        let before_writes = SystemTime::now();
+        std::thread::sleep(Duration::from_millis(1));
+        log.record(b"a", || None);
+        log.record(b"a", || image(1));
+        std::thread::sleep(Duration::from_millis(1));
+        let after_writes = SystemTime::now();
+        let at = ReadPoint::Time(before_writes);
+        assert_eq!(log.get(b"a", &at), Some(&None));
+        assert_eq!(log.get(b"a", &ReadPoint::Time(after_writes)), None);
+        assert!(log.retains(before_writes));
+        assert!(!log.retains(before_writes - Duration::from_secs(7200)));
+        assert!(!VersionLog::new(None).retains(before_writes));
+
+        // Versions older than the retention window are released
+        let mut log = VersionLog::new(Some(Duration::ZERO));
+        log.record(b"a", || None);
+        log.prune();
+        assert!(log.versions.is_empty());
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use super::Storage;
+use crate::prelude::*;
+
+/// The keys a watch is notified of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchTarget {
+    Key(Vec<u8>),
+    Prefix(Vec<u8>),
+}
+
+impl WatchTarget {
+    fn matches(&self, key: &[u8]) -> bool {
+        match self {
+            WatchTarget::Key(k) => k == key,
+            WatchTarget::Prefix(prefix) => key.starts_with(prefix),
+        }
+    }
+}
+
+/// Committed write to a watched key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    c_id: ContainerId,
+    key: Vec<u8>,
+    value: Option<Vec<u8>>, // None if the key was deleted
+    txn_id: TxnId,
+}
+
+impl WatchEvent {
+    pub fn container_id(&self) -> ContainerId {
+        self.c_id
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Value of the key when the transaction committed, or None if it was deleted.
+    pub fn value(&self) -> Option<&[u8]> {
+        self.value.as_deref()
+    }
+
+    pub fn txn_id(&self) -> TxnId {
+        self.txn_id
+    }
+}
+
+type WatchCallback = Box<dyn Fn(&WatchEvent) + Send + Sync>;
+
+struct Watcher {
+    target: WatchTarget,
+    callback: Option<WatchCallback>, // called instead of queueing the events
+    events: Mutex<VecDeque<WatchEvent>>,
+    notified: Condvar,
+}
+
+impl Watcher {
+    fn notify(&self, event: &WatchEvent) {
+        match &self.callback {
+            Some(callback) => callback(event),
+            None => {
+                self.events.lock().unwrap().push_back(event.clone());
+                self.notified.notify_all();
+            }
+        }
+    }
+}
+
+/// The watches of a container. The count lets writes check for watches without locking.
+#[derive(Default)]
+pub struct Watchers {
+    count: AtomicUsize,
+    list: Mutex<Vec<Arc<Watcher>>>,
+}
+
+impl Watchers {
+    pub fn is_empty(&self) -> bool {
+        self.count.load(Ordering::Acquire) == 0
+    }
+
+    // Delivers the event to the matching watches. Callbacks run without the lock so
+    // that they can create and drop watches.
+    pub fn notify(&self, c_id: ContainerId, txn_id: TxnId, key: Vec<u8>, value: Option<Vec<u8>>) {
+        let watchers: Vec<_> = self
+            .list
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|watcher| watcher.target.matches(&key))
+            .cloned()
+            .collect();
+        if watchers.is_empty() {
+            return;
+        }
+        let event = WatchEvent {
+            c_id,
+            key,
+            value,
+            txn_id,
+        };
+        for watcher in watchers {
+            watcher.notify(&event);
+        }
+    }
+}
+
+/// Watch of a key or a prefix of a container, notified when a transaction that wrote a
+/// watched key commits. The events are queued until they are taken with `try_next`,
+/// `wait` or `wait_timeout`, unless the watch was created with a callback, which is then
+/// called by the committing thread instead. Dropping the watch stops it.
+///
+/// Writes are only tracked while a container is watched, so a transaction that wrote the
+/// key before the watch was created is not seen. Create the watch before reading the
+/// current value.
+pub struct Watch {
+    storage: Arc<Storage>,
+    watcher: Arc<Watcher>,
+}
+
+impl Watch {
+    pub(super) fn new(
+        storage: Arc<Storage>,
+        target: WatchTarget,
+        callback: Option<WatchCallback>,
+    ) -> Self {
+        let watcher = Arc::new(Watcher {
+            target,
+            callback,
+            events: Mutex::new(VecDeque::new()),
+            notified: Condvar::new(),
+        });
+        let watchers = &storage.watchers;
+        watchers.list.lock().unwrap().push(Arc::clone(&watcher));
+        watchers.count.fetch_add(1, Ordering::AcqRel);
+        Watch { storage, watcher }
+    }
+
+    pub fn target(&self) -> &WatchTarget {
+        &self.watcher.target
+    }
+
+    /// The next event if there is one already.
+    pub fn try_next(&self) -> Option<WatchEvent> {
+        self.watcher.events.lock().unwrap().pop_front()
+    }
+
+    /// The next event, waiting for it if needed.
+    pub fn wait(&self) -> WatchEvent {
+        let mut events = self.watcher.events.lock().unwrap();
+        loop {
+            if let Some(event) = events.pop_front() {
+                return event;
+            }
+            events = self.watcher.notified.wait(events).unwrap();
+        }
+    }
+
+    /// The next event, waiting up to `timeout` for it.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<WatchEvent> {
+        let deadline = Instant::now() + timeout;
+        let mut events = self.watcher.events.lock().unwrap();
+        loop {
+            if let Some(event) = events.pop_front() {
+                return Some(event);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            events = self
+                .watcher
+                .notified
+                .wait_timeout(events, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        let watchers = &self.storage.watchers;
+        watchers
+            .list
+            .lock()
+            .unwrap()
+            .retain(|watcher| !Arc::ptr_eq(watcher, &self.watcher));
+        watchers.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,38 @@
+// Self-delimiting encoding of byte strings, shared by the typed keys and the secondary
+// keys of indexes.
+//
+// Byte strings are terminated by 0x00 0x00 and 0x00 in the content is escaped as
+// 0x00 0xFF. The terminator sorts before any content, so a string sorts before its
+// extensions and the byte order of the strings is preserved.
+
+use crate::prelude::*;
+
+pub fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    for &b in bytes {
+        buf.push(b);
+        if b == 0 {
+            buf.push(0xFF);
+        }
+    }
+    buf.extend_from_slice(&[0, 0]);
+}
+
+// Decodes a byte string from the front of bytes and advances bytes past it.
+pub fn decode_bytes(bytes: &mut &[u8]) -> Result<Vec<u8>, Status> {
+    let mut next = || {
+        let (&b, rest) = bytes.split_first().ok_or(Status::Error)?;
+        *bytes = rest;
+        Ok(b)
+    };
+    let mut decoded = Vec::new();
+    loop {
+        match next()? {
+            0 => match next()? {
+                0 => return Ok(decoded),
+                0xFF => decoded.push(0),
+                _ => return Err(Status::Error),
+            },
+            b => decoded.push(b),
+        }
+    }
+}
@@ -1,25 +1,48 @@
+mod guard;
 mod inmem;
+mod keys;
+mod retry;
 mod rwlatch;
 mod txn_storage_trait;
+mod typed;
 
-pub use crate::inmem::{InMemDummyTxnHandle, InMemIterator, InMemStorage};
+pub use crate::inmem::{
+    ChangeStream, InMemDummyTxnHandle, InMemIterator, InMemStorage, Watch, WatchEvent, WatchTarget,
+};
+pub use guard::{IteratorGuard, TxnGuard};
+pub use retry::run_txn;
+pub use rwlatch::LatchMode;
 pub use txn_storage_trait::{
-    ContainerId, ContainerOptions, ContainerType, DBOptions, DatabaseId, ScanOptions, Status,
-    TxnOptions, TxnStorageTrait,
+    Change, ChangePosition, ContainerId, ContainerOptions, ContainerType, DBOptions, DatabaseId,
+    IndexOptions, KeyExtractor, MergeOperator, SavepointId, ScanOptions, Status, StorageError,
+    TxnId, TxnInfo, TxnOptions, TxnState, TxnStorageTrait,
 };
+pub use typed::{KeyCodec, TypedContainer, TypedIterator};
 
 pub mod prelude {
     pub use crate::{
-        ContainerId, ContainerOptions, ContainerType, DBOptions, DatabaseId, InMemDummyTxnHandle,
-        InMemIterator, InMemStorage, ScanOptions, Status, TxnOptions, TxnStorageTrait,
+        run_txn, Change, ChangePosition, ChangeStream, ContainerId, ContainerOptions,
+        ContainerType, DBOptions, DatabaseId, InMemDummyTxnHandle, InMemIterator, InMemStorage,
+        IndexOptions, IteratorGuard, KeyCodec, KeyExtractor, LatchMode, MergeOperator, SavepointId,
+        ScanOptions, Status, StorageError, TxnGuard, TxnId, TxnInfo, TxnOptions, TxnState,
+        TxnStorageTrait, TypedContainer, TypedIterator, Watch, WatchEvent, WatchTarget,
     };
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(loom)))]
 mod tests {
     #[cfg(test)]
     use super::*;
-    use std::{sync::Arc, thread};
+    use rstest::rstest;
+    use std::{
+        collections::HashSet,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
 
     fn get_in_mem_storage() -> Arc<InMemStorage> {
         Arc::new(InMemStorage::new())
@@ -92,12 +115,108 @@ mod tests {
 
         assert!(storage.delete_value(&txn, &c_id, &key).is_ok());
         assert!(matches!(
-            storage.get_value(&txn, &c_id, &key),
+            storage.get_value(&txn, &c_id, &key).map_err(|e| e.status()),
             Err(Status::KeyNotFound)
         ));
         storage.commit_txn(&txn, false).unwrap();
     }
 
+    #[test]
+    fn test_error_context() {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, ContainerType::Hash);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let err = storage.get_value(&txn, &c_id, b"user/42").unwrap_err();
+        assert_eq!(err, Status::KeyNotFound);
+        assert_eq!(err.container_id(), Some(c_id));
+        assert_eq!(err.key(), Some(&b"user/42"[..]));
+        assert_eq!(err.txn_id(), Some(txn.txn_id()));
+        assert!(!err.is_retryable());
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Key not found (container {}, key \"user/42\", txn {})",
+                c_id,
+                txn.txn_id()
+            )
+        );
+
+        // Converts into other error types with ?
+        let boxed = || -> Result<(), Box<dyn std::error::Error>> {
+            storage.delete_value(&txn, &c_id, b"user/42")?;
+            Ok(())
+        };
+        assert_eq!(
+            boxed().unwrap_err().to_string(),
+            format!(
+                "Key not found (container {}, key \"user/42\", txn {})",
+                c_id,
+                txn.txn_id()
+            )
+        );
+        storage.commit_txn(&txn, false).unwrap();
+
+        assert!(StorageError::from(Status::TxnConflict).is_retryable());
+        let io_err = std::io::Error::new(std::io::ErrorKind::TimedOut, "disk timed out");
+        let err = StorageError::from(io_err).with_txn(7);
+        assert_eq!(err, Status::IoError);
+        assert!(err.is_retryable());
+        assert!(std::error::Error::source(&err).is_some());
+        assert_eq!(err.to_string(), "I/O error (txn 7): disk timed out");
+        let io_err = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "read only");
+        assert!(!StorageError::from(io_err).is_retryable());
+    }
+
+    #[test]
+    fn test_active_transactions() {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, ContainerType::Hash);
+        let txn1 = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let txn2 = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        assert!(txn1.txn_id() < txn2.txn_id());
+        for i in 0..3 {
+            storage
+                .insert_value(&txn1, &c_id, vec![i], vec![i])
+                .unwrap();
+        }
+        storage.delete_value(&txn1, &c_id, [0]).unwrap();
+        // Failed writes are not counted
+        assert!(storage.delete_value(&txn1, &c_id, [0]).is_err());
+
+        let active = storage.active_transactions();
+        let summary: Vec<_> = active
+            .iter()
+            .map(|t| (t.txn_id(), t.state(), t.writes()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (txn1.txn_id(), TxnState::Active, 4),
+                (txn2.txn_id(), TxnState::Active, 0)
+            ]
+        );
+        assert!(active[0].age() >= active[1].age());
+
+        assert_eq!(txn1.commit_time(), None);
+        storage.commit_txn(&txn1, false).unwrap();
+        assert_eq!(txn1.state(), TxnState::Committed);
+        assert!(txn1.commit_time().unwrap() >= txn1.begin_time());
+        assert_eq!(
+            storage.commit_txn(&txn1, false).unwrap_err(),
+            Status::TxNotFound
+        );
+        let ids: Vec<_> = storage
+            .active_transactions()
+            .iter()
+            .map(|t| t.txn_id())
+            .collect();
+        assert_eq!(ids, [txn2.txn_id()]);
+
+        // A dropped handle is not listed even if it was never finished
+        drop(txn2);
+        assert!(storage.active_transactions().is_empty());
+    }
+
     #[test]
     fn test_scan_range() {
         let storage = get_in_mem_storage();
@@ -107,31 +226,1066 @@ mod tests {
         // Insert some values
         for i in 0..4 {
             let key = vec![i];
-            let value = vec![i as u8; 4];
+            let value = vec![i; 4];
             storage.insert_value(&txn, &c_id, key, value).unwrap();
         }
         let iter_handle = storage.scan_range(&txn, &c_id, ScanOptions::new()).unwrap();
         let mut count = 0;
         while let Ok(Some((key, val))) = storage.iter_next(&iter_handle) {
             assert_eq!(key, vec![count]);
-            assert_eq!(val, vec![count as u8; 4]);
+            assert_eq!(val, vec![count; 4]);
             count += 1;
         }
         assert_eq!(count, 4);
         storage.commit_txn(&txn, false).unwrap();
     }
 
+    #[rstest]
+    #[case::btree(ContainerType::BTree)]
+    #[case::skiplist(ContainerType::SkipList)]
+    #[case::art(ContainerType::Art)]
+    fn test_delete_range(#[case] c_type: ContainerType) {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, c_type);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        for i in 0..10 {
+            storage.insert_value(&txn, &c_id, vec![i], vec![i]).unwrap();
+        }
+        storage.delete_range(&txn, &c_id, [3], [7]).unwrap();
+        for i in 0..10 {
+            let exists = storage.check_value(&txn, &c_id, [i]).unwrap();
+            assert_eq!(exists, !(3..7).contains(&i));
+        }
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[test]
+    fn test_delete_range_on_hash_container() {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, ContainerType::Hash);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        assert_eq!(
+            storage
+                .delete_range(&txn, &c_id, [0], [1])
+                .map_err(|e| e.status()),
+            Err(Status::Error)
+        );
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[rstest]
+    #[case::hash(ContainerType::Hash)]
+    #[case::btree(ContainerType::BTree)]
+    #[case::concurrent_hash(ContainerType::ConcurrentHash)]
+    #[case::skiplist(ContainerType::SkipList)]
+    #[case::art(ContainerType::Art)]
+    fn test_truncate_container(#[case] c_type: ContainerType) {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, c_type);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        for i in 0..10 {
+            storage.insert_value(&txn, &c_id, vec![i], vec![i]).unwrap();
+        }
+        storage.truncate_container(&txn, &c_id).unwrap();
+        let iter_handle = storage.scan_range(&txn, &c_id, ScanOptions::new()).unwrap();
+        assert_eq!(storage.iter_next(&iter_handle), Ok(None));
+        drop(iter_handle);
+        // The container is still usable after truncation
+        storage.insert_value(&txn, &c_id, vec![0], vec![1]).unwrap();
+        assert_eq!(storage.get_value(&txn, &c_id, [0]), Ok(vec![1]));
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[rstest]
+    #[case::hash(ContainerType::Hash)]
+    #[case::btree(ContainerType::BTree)]
+    #[case::concurrent_hash(ContainerType::ConcurrentHash)]
+    #[case::skiplist(ContainerType::SkipList)]
+    #[case::art(ContainerType::Art)]
+    fn test_ttl_expiration(#[case] c_type: ContainerType) {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, c_type);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let ttl = Duration::from_millis(50);
+        storage
+            .insert_value_with_ttl(&txn, &c_id, vec![0], vec![0], ttl)
+            .unwrap();
+        storage.insert_value(&txn, &c_id, vec![1], vec![1]).unwrap();
+        assert_eq!(storage.get_value(&txn, &c_id, [0]), Ok(vec![0]));
+
+        thread::sleep(ttl * 2);
+        assert_eq!(
+            storage.get_value(&txn, &c_id, [0]).map_err(|e| e.status()),
+            Err(Status::KeyNotFound)
+        );
+        assert_eq!(storage.check_value(&txn, &c_id, [0]), Ok(false));
+        let iter_handle = storage.scan_range(&txn, &c_id, ScanOptions::new()).unwrap();
+        assert_eq!(
+            storage.iter_next(&iter_handle),
+            Ok(Some((vec![1], vec![1])))
+        );
+        assert_eq!(storage.iter_next(&iter_handle), Ok(None));
+        drop(iter_handle);
+
+        // An expired key can be inserted again
+        storage.insert_value(&txn, &c_id, vec![0], vec![2]).unwrap();
+        storage.sweep_expired();
+        assert_eq!(storage.get_value(&txn, &c_id, [0]), Ok(vec![2]));
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[test]
+    fn test_update_value_with_ttl() {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, ContainerType::Hash);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let ttl = Duration::from_millis(50);
+        storage
+            .insert_value_with_ttl(&txn, &c_id, vec![0], vec![0], ttl)
+            .unwrap();
+        // Updating without ttl makes the key persistent
+        storage.update_value(&txn, &c_id, [0], vec![1]).unwrap();
+        storage.insert_value(&txn, &c_id, vec![1], vec![1]).unwrap();
+        storage
+            .update_value_with_ttl(&txn, &c_id, [1], vec![2], ttl)
+            .unwrap();
+
+        thread::sleep(ttl * 2);
+        storage.sweep_expired();
+        assert_eq!(storage.get_value(&txn, &c_id, [0]), Ok(vec![1]));
+        assert_eq!(
+            storage.get_value(&txn, &c_id, [1]).map_err(|e| e.status()),
+            Err(Status::KeyNotFound)
+        );
+        assert_eq!(
+            storage
+                .update_value(&txn, &c_id, [1], vec![3])
+                .map_err(|e| e.status()),
+            Err(Status::KeyNotFound)
+        );
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[test]
+    fn test_background_sweeper() {
+        let storage = get_in_mem_storage();
+        storage.start_sweeper(Duration::from_millis(10));
+        let (db_id, c_id) = setup_table(&storage, ContainerType::BTree);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        for i in 0..100 {
+            storage
+                .insert_value_with_ttl(&txn, &c_id, vec![i], vec![i], Duration::from_millis(20))
+                .unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+        for i in 0..100 {
+            assert_eq!(storage.check_value(&txn, &c_id, [i]), Ok(false));
+        }
+        storage.commit_txn(&txn, false).unwrap();
+        // Dropping the storage stops the sweeper
+        drop(storage);
+    }
+
+    #[test]
+    fn test_sweeper_during_delete_db() {
+        let storage = get_in_mem_storage();
+        storage.start_sweeper(Duration::from_millis(1));
+        let done = Arc::new(AtomicBool::new(false));
+        let sweeper = {
+            let (storage, done) = (storage.clone(), done.clone());
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    storage.sweep_expired();
+                }
+            })
+        };
+        for _ in 0..200 {
+            let db_id = storage.open_db(DBOptions::new("test_db")).unwrap();
+            let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+            // Enough containers for the sweeps to overlap with the deletes
+            for c in 0..20 {
+                let options = ContainerOptions::new(&format!("c{}", c), ContainerType::BTree);
+                let c_id = storage.create_container(&txn, &db_id, options).unwrap();
+                for i in 0..10 {
+                    storage
+                        .insert_value_with_ttl(&txn, &c_id, vec![i], vec![i], Duration::ZERO)
+                        .unwrap();
+                }
+            }
+            storage.commit_txn(&txn, false).unwrap();
+            storage.delete_db(&db_id).unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        sweeper.join().unwrap();
+    }
+
+    fn setup_counter_table(
+        storage: &InMemStorage,
+        c_type: ContainerType,
+    ) -> (DatabaseId, ContainerId) {
+        let add: MergeOperator = Arc::new(|val, operands| {
+            let mut sum = val.map_or(0, |v| u64::from_be_bytes(v.try_into().unwrap()));
+            for op in operands {
+                sum += u64::from_be_bytes(op.as_slice().try_into().unwrap());
+            }
+            sum.to_be_bytes().to_vec()
+        });
+        let db_id = storage.open_db(DBOptions::new("test_db")).unwrap();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let c_id = storage
+            .create_container(
+                &txn,
+                &db_id,
+                ContainerOptions::new("counters", c_type).with_merge_operator(add),
+            )
+            .unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+        (db_id, c_id)
+    }
+
+    #[rstest]
+    #[case::hash(ContainerType::Hash)]
+    #[case::btree(ContainerType::BTree)]
+    #[case::concurrent_hash(ContainerType::ConcurrentHash)]
+    #[case::skiplist(ContainerType::SkipList)]
+    #[case::art(ContainerType::Art)]
+    fn test_merge_value(#[case] c_type: ContainerType) {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_counter_table(&storage, c_type);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        // Merge into a missing key
+        for _ in 0..100 {
+            storage
+                .merge_value(&txn, &c_id, vec![0], 1u64.to_be_bytes().to_vec())
+                .unwrap();
+        }
+        assert_eq!(
+            storage.get_value(&txn, &c_id, [0]),
+            Ok(100u64.to_be_bytes().to_vec())
+        );
+        // Merge into an existing value
+        storage
+            .insert_value(&txn, &c_id, vec![1], 10u64.to_be_bytes().to_vec())
+            .unwrap();
+        storage
+            .merge_value(&txn, &c_id, vec![1], 5u64.to_be_bytes().to_vec())
+            .unwrap();
+        let iter_handle = storage.scan_range(&txn, &c_id, ScanOptions::new()).unwrap();
+        let mut kvs = Vec::new();
+        while let Ok(Some(kv)) = storage.iter_next(&iter_handle) {
+            kvs.push(kv);
+        }
+        kvs.sort();
+        assert_eq!(
+            kvs,
+            vec![
+                (vec![0], 100u64.to_be_bytes().to_vec()),
+                (vec![1], 15u64.to_be_bytes().to_vec())
+            ]
+        );
+        drop(iter_handle);
+        // Update discards the pending operands
+        storage
+            .update_value(&txn, &c_id, [0], 7u64.to_be_bytes().to_vec())
+            .unwrap();
+        assert_eq!(
+            storage.get_value(&txn, &c_id, [0]),
+            Ok(7u64.to_be_bytes().to_vec())
+        );
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[test]
+    fn test_merge_value_without_merge_operator() {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, ContainerType::Hash);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        assert_eq!(
+            storage
+                .merge_value(&txn, &c_id, vec![0], vec![1])
+                .map_err(|e| e.status()),
+            Err(Status::Error)
+        );
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    // Index the values by their first byte
+    fn first_byte_index() -> IndexOptions {
+        let extractor: KeyExtractor = Arc::new(|_, val| val.first().map(|b| vec![*b]));
+        IndexOptions::new("first_byte", extractor)
+    }
+
+    #[rstest]
+    #[case::hash(ContainerType::Hash)]
+    #[case::btree(ContainerType::BTree)]
+    #[case::concurrent_hash(ContainerType::ConcurrentHash)]
+    #[case::skiplist(ContainerType::SkipList)]
+    #[case::art(ContainerType::Art)]
+    fn test_secondary_index(#[case] c_type: ContainerType) {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, c_type);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        // Existing values are indexed when the index is created
+        storage
+            .insert_value(&txn, &c_id, vec![0], vec![1, 0])
+            .unwrap();
+        let idx_id = storage
+            .create_index(&txn, &db_id, &c_id, first_byte_index())
+            .unwrap();
+        storage
+            .insert_value(&txn, &c_id, vec![1], vec![1, 1])
+            .unwrap();
+        storage.insert_value(&txn, &c_id, vec![2], vec![2]).unwrap();
+        storage.insert_value(&txn, &c_id, vec![3], vec![]).unwrap(); // Not indexed
+        assert_eq!(
+            storage.lookup_index(&txn, &idx_id, [1]),
+            Ok(vec![vec![0], vec![1]])
+        );
+        assert_eq!(storage.lookup_index(&txn, &idx_id, [2]), Ok(vec![vec![2]]));
+
+        storage.update_value(&txn, &c_id, [1], vec![2]).unwrap();
+        storage.delete_value(&txn, &c_id, [0]).unwrap();
+        assert_eq!(storage.lookup_index(&txn, &idx_id, [1]), Ok(vec![]));
+        assert_eq!(
+            storage.lookup_index(&txn, &idx_id, [2]),
+            Ok(vec![vec![1], vec![2]])
+        );
+
+        // Scanning the index yields the primary keys in secondary key order
+        storage.insert_value(&txn, &c_id, vec![4], vec![0]).unwrap();
+        let iter_handle = storage
+            .scan_range(&txn, &idx_id, ScanOptions::new())
+            .unwrap();
+        let mut primary_keys = Vec::new();
+        while let Ok(Some((_, primary_key))) = storage.iter_next(&iter_handle) {
+            primary_keys.push(primary_key);
+        }
+        assert_eq!(primary_keys, vec![vec![4], vec![1], vec![2]]);
+        drop(iter_handle);
+
+        storage.truncate_container(&txn, &c_id).unwrap();
+        assert_eq!(storage.lookup_index(&txn, &idx_id, [2]), Ok(vec![]));
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[test]
+    fn test_secondary_index_with_delete_range() {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, ContainerType::BTree);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let idx_id = storage
+            .create_index(&txn, &db_id, &c_id, first_byte_index())
+            .unwrap();
+        for i in 0..10 {
+            storage.insert_value(&txn, &c_id, vec![i], vec![0]).unwrap();
+        }
+        storage.delete_range(&txn, &c_id, [3], [7]).unwrap();
+        assert_eq!(
+            storage.lookup_index(&txn, &idx_id, [0]),
+            Ok(vec![vec![0], vec![1], vec![2], vec![7], vec![8], vec![9]])
+        );
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[rstest]
+    #[case::hash(ContainerType::Hash)]
+    #[case::btree(ContainerType::BTree)]
+    #[case::concurrent_hash(ContainerType::ConcurrentHash)]
+    #[case::skiplist(ContainerType::SkipList)]
+    #[case::art(ContainerType::Art)]
+    fn test_rollback_to_savepoint(#[case] c_type: ContainerType) {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, c_type);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        for i in 0..4 {
+            storage.insert_value(&txn, &c_id, vec![i], vec![i]).unwrap();
+        }
+        let sp = storage.savepoint(&txn).unwrap();
+        storage.insert_value(&txn, &c_id, vec![4], vec![4]).unwrap();
+        storage.update_value(&txn, &c_id, [0], vec![9]).unwrap();
+        storage.update_value(&txn, &c_id, [0], vec![8]).unwrap();
+        storage.delete_value(&txn, &c_id, [1]).unwrap();
+        let nested = storage.savepoint(&txn).unwrap();
+        storage.delete_value(&txn, &c_id, [2]).unwrap();
+
+        storage.rollback_to(&txn, nested).unwrap();
+        assert_eq!(storage.get_value(&txn, &c_id, [2]), Ok(vec![2]));
+        assert_eq!(storage.get_value(&txn, &c_id, [0]), Ok(vec![8]));
+
+        storage.rollback_to(&txn, sp).unwrap();
+        for i in 0..4 {
+            assert_eq!(storage.get_value(&txn, &c_id, [i]), Ok(vec![i]));
+        }
+        assert!(!storage.check_value(&txn, &c_id, [4]).unwrap());
+        // The savepoints created after sp are released
+        assert_eq!(
+            storage.rollback_to(&txn, nested).map_err(|e| e.status()),
+            Err(Status::Error)
+        );
+
+        // The transaction continues and sp can be rolled back to again
+        storage.insert_value(&txn, &c_id, vec![5], vec![5]).unwrap();
+        storage.rollback_to(&txn, sp).unwrap();
+        assert!(!storage.check_value(&txn, &c_id, [5]).unwrap());
+        storage.insert_value(&txn, &c_id, vec![6], vec![6]).unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        assert_eq!(storage.get_value(&txn, &c_id, [6]), Ok(vec![6]));
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[test]
+    fn test_rollback_to_savepoint_with_index() {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, ContainerType::BTree);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let idx_id = storage
+            .create_index(&txn, &db_id, &c_id, first_byte_index())
+            .unwrap();
+        for i in 0..6 {
+            storage.insert_value(&txn, &c_id, vec![i], vec![1]).unwrap();
+        }
+        let sp = storage.savepoint(&txn).unwrap();
+        storage.delete_range(&txn, &c_id, [2], [4]).unwrap();
+        storage.update_value(&txn, &c_id, [0], vec![2]).unwrap();
+        storage.rollback_to(&txn, sp).unwrap();
+        assert_eq!(
+            storage.lookup_index(&txn, &idx_id, [1]),
+            Ok((0..6).map(|i| vec![i]).collect())
+        );
+        assert_eq!(storage.lookup_index(&txn, &idx_id, [2]), Ok(vec![]));
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[test]
+    fn test_two_phase_commit() {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, ContainerType::Hash);
+        let options = TxnOptions::default().with_two_phase_commit();
+
+        let txn = storage.begin_txn(&db_id, options.clone()).unwrap();
+        storage.insert_value(&txn, &c_id, vec![0], vec![0]).unwrap();
+        storage.prepare_txn(&txn).unwrap();
+        assert_eq!(txn.state(), TxnState::Prepared);
+        assert_eq!(storage.active_transactions()[0].state(), TxnState::Prepared);
+        // A prepared transaction can no longer write or be prepared again
+        assert_eq!(
+            storage
+                .insert_value(&txn, &c_id, vec![1], vec![1])
+                .unwrap_err(),
+            Status::Error
+        );
+        assert_eq!(storage.prepare_txn(&txn).unwrap_err(), Status::TxNotFound);
+        storage.commit_txn(&txn, false).unwrap();
+        assert_eq!(txn.state(), TxnState::Committed);
+
+        // Aborting a prepared transaction undoes its writes
+        let txn = storage.begin_txn(&db_id, options.clone()).unwrap();
+        storage.update_value(&txn, &c_id, [0], vec![2]).unwrap();
+        storage.insert_value(&txn, &c_id, vec![1], vec![1]).unwrap();
+        storage.prepare_txn(&txn).unwrap();
+        storage.abort_txn(&txn).unwrap();
+        assert_eq!(
+            storage
+                .insert_value(&txn, &c_id, vec![2], vec![2])
+                .unwrap_err(),
+            Status::TxNotFound
+        );
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        assert_eq!(storage.get_value(&txn, &c_id, [0]), Ok(vec![0]));
+        assert_eq!(
+            storage.get_value(&txn, &c_id, [1]).map_err(|e| e.status()),
+            Err(Status::KeyNotFound)
+        );
+
+        // Transactions not begun for two-phase commit cannot be prepared
+        assert_eq!(storage.prepare_txn(&txn).unwrap_err(), Status::Error);
+        storage.commit_txn(&txn, false).unwrap();
+
+        // A prepared transaction whose handle is lost is found by id and finished
+        for commit in [false, true] {
+            let txn = storage.begin_txn(&db_id, options.clone()).unwrap();
+            storage.update_value(&txn, &c_id, [0], vec![3]).unwrap();
+            storage.prepare_txn(&txn).unwrap();
+            storage.drop_txn(txn).unwrap();
+            let active = storage.active_transactions();
+            assert_eq!(active.len(), 1);
+            assert_eq!(active[0].state(), TxnState::Prepared);
+            let txn = storage.prepared_txn(active[0].txn_id()).unwrap();
+            if commit {
+                storage.commit_txn(&txn, false).unwrap();
+            } else {
+                storage.abort_txn(&txn).unwrap();
+            }
+            storage.drop_txn(txn).unwrap();
+            assert!(storage.active_transactions().is_empty());
+            assert!(matches!(
+                storage
+                    .prepared_txn(active[0].txn_id())
+                    .map_err(|e| e.status()),
+                Err(Status::TxNotFound)
+            ));
+            let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+            let expected = if commit { vec![3] } else { vec![0] };
+            assert_eq!(storage.get_value(&txn, &c_id, [0]), Ok(expected));
+            storage.commit_txn(&txn, false).unwrap();
+        }
+    }
+
+    #[rstest]
+    #[case::hash(ContainerType::Hash)]
+    #[case::btree(ContainerType::BTree)]
+    #[case::concurrent_hash(ContainerType::ConcurrentHash)]
+    #[case::skiplist(ContainerType::SkipList)]
+    #[case::art(ContainerType::Art)]
+    fn test_snapshot(#[case] c_type: ContainerType) {
+        let storage = get_in_mem_storage();
+        let ordered = !matches!(c_type, ContainerType::Hash | ContainerType::ConcurrentHash);
+        let (db_id, c_id) = setup_table(&storage, c_type);
+        // More keys than a snapshot scan copies at a time
+        let keys: Vec<Vec<u8>> = (0..600u16).map(|i| i.to_be_bytes().to_vec()).collect();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        for key in &keys {
+            storage
+                .insert_value(&txn, &c_id, key.clone(), key.clone())
+                .unwrap();
+        }
+        storage.commit_txn(&txn, false).unwrap();
+
+        let snapshot = storage.begin_snapshot(&db_id).unwrap();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        storage
+            .update_value(&txn, &c_id, &keys[0], vec![0])
+            .unwrap();
+        storage
+            .update_value(&txn, &c_id, &keys[0], vec![1])
+            .unwrap();
+        storage.delete_value(&txn, &c_id, &keys[1]).unwrap();
+        storage
+            .insert_value(&txn, &c_id, vec![0xFF; 3], vec![0])
+            .unwrap();
+        assert_eq!(storage.get_value(&txn, &c_id, &keys[0]), Ok(vec![1]));
+
+        assert_eq!(
+            storage.get_value(&snapshot, &c_id, &keys[0]),
+            Ok(keys[0].clone())
+        );
+        assert!(storage.check_value(&snapshot, &c_id, &keys[1]).unwrap());
+        assert!(!storage.check_value(&snapshot, &c_id, [0xFF; 3]).unwrap());
+        assert_eq!(
+            storage
+                .insert_value(&snapshot, &c_id, vec![0], vec![0])
+                .unwrap_err(),
+            Status::Error
+        );
+
+        // The scan holds no latch or lock between next() calls, so writes go through
+        // mid-scan
+        let iter = storage
+            .scan_range(&snapshot, &c_id, ScanOptions::new())
+            .unwrap();
+        let mut scanned: Vec<_> = (0..300)
+            .map(|_| storage.iter_next(&iter).unwrap().unwrap())
+            .collect();
+        storage.delete_value(&txn, &c_id, &keys[599]).unwrap();
+        storage
+            .update_value(&txn, &c_id, &keys[300], vec![1])
+            .unwrap();
+        while let Some(entry) = storage.iter_next(&iter).unwrap() {
+            scanned.push(entry);
+        }
+        if ordered {
+            assert!(scanned.windows(2).all(|w| w[0].0 < w[1].0));
+        }
+        scanned.sort();
+        let expected: Vec<_> = keys.iter().map(|k| (k.clone(), k.clone())).collect();
+        assert_eq!(scanned, expected);
+        storage.commit_txn(&txn, false).unwrap();
+        storage.commit_txn(&snapshot, false).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_sharded_hash() {
+        let storage = get_in_mem_storage();
+        let db_id = storage.open_db(DBOptions::new("test_db")).unwrap();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let options = ContainerOptions::new("test_container", ContainerType::Hash).with_shards(4);
+        let c_id = storage.create_container(&txn, &db_id, options).unwrap();
+        // Several batches per shard
+        let keys: Vec<Vec<u8>> = (0..2000u16).map(|i| i.to_be_bytes().to_vec()).collect();
+        for key in &keys {
+            storage
+                .insert_value(&txn, &c_id, key.clone(), key.clone())
+                .unwrap();
+        }
+        storage.commit_txn(&txn, false).unwrap();
+
+        let snapshot = storage.begin_snapshot(&db_id).unwrap();
+        let iter = storage
+            .scan_range(&snapshot, &c_id, ScanOptions::new())
+            .unwrap();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let mut scanned = Vec::new();
+        let mut written = keys.iter();
+        // Writes to all the shards go through between the batches of the scan
+        while let Some(entry) = storage.iter_next(&iter).unwrap() {
+            scanned.push(entry);
+            if scanned.len() % 100 == 0 {
+                let key = written.next().unwrap();
+                storage.delete_value(&txn, &c_id, key).unwrap();
+                let key = written.next().unwrap();
+                storage.update_value(&txn, &c_id, key, vec![]).unwrap();
+                let new_key = [key.as_slice(), &[0]].concat();
+                storage.insert_value(&txn, &c_id, new_key, vec![]).unwrap();
+            }
+        }
+        scanned.sort();
+        let expected: Vec<_> = keys.iter().map(|k| (k.clone(), k.clone())).collect();
+        assert_eq!(scanned, expected);
+        storage.commit_txn(&txn, false).unwrap();
+        storage.commit_txn(&snapshot, false).unwrap();
+    }
+
     #[test]
-    fn test_concurrent_insert() {
+    fn test_snapshot_with_index() {
         let storage = get_in_mem_storage();
         let (db_id, c_id) = setup_table(&storage, ContainerType::BTree);
+        let txn = TxnGuard::begin(&*storage, &db_id, TxnOptions::default()).unwrap();
+        let idx_id = storage
+            .create_index(&txn, &db_id, &c_id, first_byte_index())
+            .unwrap();
+        for i in 0..4 {
+            storage.insert_value(&txn, &c_id, vec![i], vec![1]).unwrap();
+        }
+        txn.commit(false).unwrap();
+
+        let snapshot = TxnGuard::snapshot(&*storage, &db_id).unwrap();
+        let txn = TxnGuard::begin(&*storage, &db_id, TxnOptions::default()).unwrap();
+        storage.update_value(&txn, &c_id, [0], vec![2]).unwrap();
+        storage.truncate_container(&txn, &c_id).unwrap();
+        let new_c_id = storage
+            .create_container(
+                &txn,
+                &db_id,
+                ContainerOptions::new("new_container", ContainerType::Hash),
+            )
+            .unwrap();
+        txn.commit(false).unwrap();
+
+        assert_eq!(
+            storage.lookup_index(&snapshot, &idx_id, [1]),
+            Ok((0..4).map(|i| vec![i]).collect())
+        );
+        assert_eq!(storage.lookup_index(&snapshot, &idx_id, [2]), Ok(vec![]));
+        let keys: Vec<_> = snapshot
+            .scan(&c_id, ScanOptions::new())
+            .unwrap()
+            .map(|r| r.unwrap().0)
+            .collect();
+        assert_eq!(keys, [[0], [1], [2], [3]]);
+        // Containers created after the snapshot began are not visible
+        assert_eq!(
+            storage.get_value(&snapshot, &new_c_id, [0]).unwrap_err(),
+            Status::ContainerNotFound
+        );
+        drop(snapshot);
+
+        let snapshot = TxnGuard::snapshot(&*storage, &db_id).unwrap();
+        assert_eq!(
+            storage.get_value(&snapshot, &c_id, [0]).unwrap_err(),
+            Status::KeyNotFound
+        );
+    }
+
+    #[rstest]
+    #[case::hash(ContainerType::Hash)]
+    #[case::btree(ContainerType::BTree)]
+    #[case::skiplist(ContainerType::SkipList)]
+    fn test_read_at_past_time(#[case] c_type: ContainerType) {
+        let storage = get_in_mem_storage();
+        let db_id = storage.open_db(DBOptions::new("test_db")).unwrap();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let options = ContainerOptions::new("audited", c_type)
+            .with_version_retention(Duration::from_secs(3600));
+        let c_id = storage.create_container(&txn, &db_id, options).unwrap();
+        let options = ContainerOptions::new("not_audited", ContainerType::BTree);
+        let other_id = storage.create_container(&txn, &db_id, options).unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+
+        // Takes the time between writes
+        let now = || {
+            thread::sleep(Duration::from_millis(1));
+            let now = std::time::SystemTime::now();
+            thread::sleep(Duration::from_millis(1));
+            now
+        };
+        let t0 = now();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        storage.insert_value(&txn, &c_id, vec![0], vec![1]).unwrap();
+        storage.insert_value(&txn, &c_id, vec![1], vec![1]).unwrap();
+        let t1 = now();
+        storage.update_value(&txn, &c_id, [0], vec![2]).unwrap();
+        storage.delete_value(&txn, &c_id, [1]).unwrap();
+        let t2 = now();
+        storage.delete_value(&txn, &c_id, [0]).unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+
+        let read_at = |time| {
+            let txn = storage
+                .begin_txn(&db_id, TxnOptions::default().with_read_time(time))
+                .unwrap();
+            let iter = storage.scan_range(&txn, &c_id, ScanOptions::new()).unwrap();
+            let mut entries = Vec::new();
+            while let Some(entry) = storage.iter_next(&iter).unwrap() {
+                entries.push(entry);
+            }
+            entries.sort();
+            entries
+        };
+        assert_eq!(read_at(t0), []);
+        assert_eq!(read_at(t1), [(vec![0], vec![1]), (vec![1], vec![1])]);
+        assert_eq!(read_at(t2), [(vec![0], vec![2])]);
+
+        let txn = storage
+            .begin_txn(&db_id, TxnOptions::default().with_read_time(t1))
+            .unwrap();
+        assert_eq!(storage.get_value(&txn, &c_id, [0]), Ok(vec![1]));
+        assert_eq!(
+            storage.delete_value(&txn, &c_id, [0]).unwrap_err(),
+            Status::Error
+        );
+        // Containers without retention and times before the retention window cannot be read
+        assert_eq!(
+            storage.get_value(&txn, &other_id, [0]).unwrap_err(),
+            Status::Error
+        );
+        // Expiries are checked at the read time
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let ttl = Duration::from_millis(50);
+        storage
+            .insert_value_with_ttl(&txn, &c_id, vec![2], vec![1], ttl)
+            .unwrap();
+        let t3 = now();
+        storage.update_value(&txn, &c_id, [2], vec![2]).unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+        thread::sleep(ttl * 2);
+        let txn = storage
+            .begin_txn(&db_id, TxnOptions::default().with_read_time(t3))
+            .unwrap();
+        assert_eq!(storage.get_value(&txn, &c_id, [2]), Ok(vec![1]));
+        assert_eq!(read_at(t3), [(vec![2], vec![1])]);
+
+        // Keys that expired since the read time are kept for it, even if they were never
+        // written again and the expired keys were swept
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        storage
+            .insert_value_with_ttl(&txn, &c_id, vec![3], vec![3], ttl)
+            .unwrap();
+        storage
+            .insert_value_with_ttl(&txn, &c_id, vec![4], vec![4], ttl)
+            .unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+        let t4 = now();
+        thread::sleep(ttl * 2);
+        storage.sweep_expired();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        assert_eq!(storage.check_value(&txn, &c_id, [3]), Ok(false));
+        // Writing over an expired key keeps its value for the reads before it expired
+        storage.insert_value(&txn, &c_id, vec![4], vec![5]).unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+        let txn = storage
+            .begin_txn(&db_id, TxnOptions::default().with_read_time(t4))
+            .unwrap();
+        assert_eq!(storage.get_value(&txn, &c_id, [3]), Ok(vec![3]));
+        assert_eq!(storage.get_value(&txn, &c_id, [4]), Ok(vec![4]));
+        assert_eq!(
+            read_at(t4),
+            [(vec![2], vec![2]), (vec![3], vec![3]), (vec![4], vec![4])]
+        );
+
+        let long_ago = t0 - Duration::from_secs(7200);
+        let txn = storage
+            .begin_txn(&db_id, TxnOptions::default().with_read_time(long_ago))
+            .unwrap();
+        assert_eq!(
+            storage.get_value(&txn, &c_id, [0]).unwrap_err(),
+            Status::Error
+        );
+    }
+
+    #[test]
+    fn test_change_capture() {
+        let storage = get_in_mem_storage();
+        let db_id = storage.open_db(DBOptions::new("test_db")).unwrap();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let options = ContainerOptions::new("captured", ContainerType::BTree).with_change_capture();
+        let c_id = storage.create_container(&txn, &db_id, options).unwrap();
+        let options = ContainerOptions::new("not_captured", ContainerType::BTree);
+        let other_id = storage.create_container(&txn, &db_id, options).unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+        let mut stream = storage.subscribe(storage.change_position()).unwrap();
+        assert_eq!(stream.try_next(), Ok(None));
+
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        storage.insert_value(&txn, &c_id, vec![0], vec![0]).unwrap();
+        storage.insert_value(&txn, &c_id, vec![1], vec![1]).unwrap();
+        storage
+            .insert_value(&txn, &other_id, vec![0], vec![0])
+            .unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+        let first_txn = txn.txn_id();
+
+        // Only the net change of a key is delivered, and keys written back are skipped
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        storage.update_value(&txn, &c_id, [0], vec![1]).unwrap();
+        storage.update_value(&txn, &c_id, [0], vec![2]).unwrap();
+        storage.update_value(&txn, &c_id, [1], vec![2]).unwrap();
+        storage.update_value(&txn, &c_id, [1], vec![1]).unwrap();
+        storage.insert_value(&txn, &c_id, vec![2], vec![2]).unwrap();
+        let sp = storage.savepoint(&txn).unwrap();
+        storage.delete_value(&txn, &c_id, [2]).unwrap();
+        storage.rollback_to(&txn, sp).unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+        let second_txn = txn.txn_id();
+
+        // Changes of aborted transactions are not delivered and their writes are undone,
+        // whether the container captures its changes or not.
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        storage.insert_value(&txn, &c_id, vec![3], vec![3]).unwrap();
+        storage
+            .insert_value(&txn, &other_id, vec![3], vec![3])
+            .unwrap();
+        storage.abort_txn(&txn).unwrap();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        assert_eq!(storage.check_value(&txn, &c_id, [3]), Ok(false));
+        assert_eq!(storage.check_value(&txn, &other_id, [3]), Ok(false));
+        storage.commit_txn(&txn, false).unwrap();
+
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        storage.truncate_container(&txn, &c_id).unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+        let third_txn = txn.txn_id();
+
+        let changes: Vec<_> = (0..7)
+            .map(|_| stream.try_next().unwrap().unwrap())
+            .collect();
+        assert_eq!(stream.try_next(), Ok(None));
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|c| {
+                assert_eq!(c.container_id(), c_id);
+                (c.txn_id(), c.key(), c.old_value(), c.new_value())
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (first_txn, &[0][..], None, Some(&[0][..])),
+                (first_txn, &[1], None, Some(&[1])),
+                (second_txn, &[0], Some(&[0][..]), Some(&[2][..])),
+                (second_txn, &[2], None, Some(&[2])),
+                (third_txn, &[0], Some(&[2]), None),
+                (third_txn, &[1], Some(&[1]), None),
+                (third_txn, &[2], Some(&[2]), None),
+            ]
+        );
+        assert!(changes
+            .windows(2)
+            .all(|w| w[0].position() + 1 == w[1].position()
+                && w[0].commit_time() <= w[1].commit_time()));
+
+        // A stream resumes from its position, and a blocked reader wakes up on commit
+        let position = changes[3].position() + 1;
+        let mut resumed = storage.subscribe(position).unwrap();
+        assert_eq!(resumed.next().unwrap(), Ok(changes[4].clone()));
+        let storage2 = storage.clone();
+        let reader = thread::spawn(move || {
+            let mut stream = storage2.subscribe(position + 3).unwrap();
+            stream.next().unwrap().unwrap()
+        });
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        storage.insert_value(&txn, &c_id, vec![3], vec![3]).unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+        assert_eq!(reader.join().unwrap().key(), &[3]);
+        assert_eq!(
+            stream
+                .next_timeout(Duration::from_secs(1))
+                .unwrap()
+                .unwrap()
+                .key(),
+            &[3]
+        );
+    }
+
+    #[test]
+    fn test_change_capture_publishes_written_values() {
+        let storage = get_in_mem_storage();
+        let db_id = storage.open_db(DBOptions::new("test_db")).unwrap();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let options = ContainerOptions::new("captured", ContainerType::BTree).with_change_capture();
+        let c_id = storage.create_container(&txn, &db_id, options).unwrap();
+        storage.insert_value(&txn, &c_id, vec![0], vec![0]).unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+        let mut stream = storage.subscribe(storage.change_position()).unwrap();
+
+        // The key is changed again before the first writer commits
+        let first = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        storage.update_value(&first, &c_id, [0], vec![1]).unwrap();
+        let second = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        storage.update_value(&second, &c_id, [0], vec![2]).unwrap();
+        storage.commit_txn(&second, false).unwrap();
+        storage.commit_txn(&first, false).unwrap();
+
+        let summary: Vec<_> = (0..2)
+            .map(|_| stream.try_next().unwrap().unwrap())
+            .map(|c| {
+                (
+                    c.txn_id(),
+                    c.old_value().map(<[u8]>::to_vec),
+                    c.new_value().map(<[u8]>::to_vec),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (second.txn_id(), Some(vec![1]), Some(vec![2])),
+                (first.txn_id(), Some(vec![0]), Some(vec![1])),
+            ]
+        );
+        assert_eq!(stream.try_next(), Ok(None));
+    }
+
+    #[test]
+    fn test_watch() {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, ContainerType::Hash);
+        let key_watch = storage
+            .watch(&c_id, WatchTarget::Key(b"config".to_vec()))
+            .unwrap();
+        let prefix_watch = storage
+            .watch(&c_id, WatchTarget::Prefix(b"job/".to_vec()))
+            .unwrap();
+        let claimed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let callback_watch = {
+            let claimed = claimed.clone();
+            storage
+                .watch_with_callback(&c_id, WatchTarget::Prefix(b"job/".to_vec()), move |e| {
+                    claimed.lock().unwrap().push(e.key().to_vec())
+                })
+                .unwrap()
+        };
+        assert_eq!(
+            storage
+                .watch(&999, WatchTarget::Key(vec![]))
+                .err()
+                .unwrap()
+                .status(),
+            Status::ContainerNotFound
+        );
+
+        // A blocked reader wakes up when the transaction commits
+        let storage2 = storage.clone();
+        let writer = thread::spawn(move || {
+            let txn = storage2.begin_txn(&db_id, TxnOptions::default()).unwrap();
+            storage2
+                .insert_value(&txn, &c_id, b"config".to_vec(), vec![1])
+                .unwrap();
+            thread::sleep(Duration::from_millis(10));
+            storage2.commit_txn(&txn, false).unwrap();
+            txn.txn_id()
+        });
+        let event = key_watch.wait();
+        assert_eq!(event.txn_id(), writer.join().unwrap());
+        assert_eq!(event.container_id(), c_id);
+        assert_eq!(event.value(), Some(&[1][..]));
+        assert_eq!(prefix_watch.try_next(), None);
+
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        storage
+            .insert_value(&txn, &c_id, b"job/1".to_vec(), vec![])
+            .unwrap();
+        storage
+            .insert_value(&txn, &c_id, b"job/2".to_vec(), vec![])
+            .unwrap();
+        storage.delete_value(&txn, &c_id, b"config").unwrap();
+        assert_eq!(key_watch.try_next(), None); // not committed yet
+        storage.commit_txn(&txn, false).unwrap();
+        assert_eq!(key_watch.try_next().unwrap().value(), None);
+        let keys: Vec<_> = std::iter::from_fn(|| prefix_watch.try_next())
+            .map(|e| e.key().to_vec())
+            .collect();
+        assert_eq!(keys, [b"job/1".to_vec(), b"job/2".to_vec()]);
+        assert_eq!(*claimed.lock().unwrap(), keys);
+
+        // Aborted transactions are not seen, and dropped watches are no longer notified
+        drop(callback_watch);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        storage.delete_value(&txn, &c_id, b"job/1").unwrap();
+        storage.abort_txn(&txn).unwrap();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        storage.delete_value(&txn, &c_id, b"job/2").unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+        assert_eq!(
+            prefix_watch
+                .wait_timeout(Duration::from_secs(1))
+                .unwrap()
+                .key(),
+            b"job/2"
+        );
+        assert_eq!(prefix_watch.try_next(), None);
+        assert_eq!(claimed.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_get_while_iterating_with_waiting_writer() {
+        let storage = Arc::new(InMemStorage::with_latch_mode(LatchMode::Blocking));
+        let (db_id, c_id) = setup_table(&storage, ContainerType::BTree);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        storage.insert_value(&txn, &c_id, vec![0], vec![0]).unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let reader = {
+            let storage = storage.clone();
+            thread::spawn(move || {
+                let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+                let iter = storage.scan_range(&txn, &c_id, ScanOptions::new()).unwrap();
+                let writer = {
+                    let storage = storage.clone();
+                    thread::spawn(move || {
+                        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+                        storage.insert_value(&txn, &c_id, vec![1], vec![1]).unwrap();
+                        storage.commit_txn(&txn, false).unwrap();
+                    })
+                };
+                // Let the writer queue up behind the iterator
+                thread::sleep(Duration::from_millis(50));
+                done_tx.send(storage.get_value(&txn, &c_id, [0])).unwrap();
+                drop(iter);
+                writer.join().unwrap();
+            })
+        };
+        let value = done_rx.recv_timeout(Duration::from_secs(5));
+        assert_eq!(
+            value.expect("get_value blocked").map_err(|e| e.status()),
+            Ok(vec![0])
+        );
+        reader.join().unwrap();
+    }
+
+    #[rstest]
+    #[case::spin(LatchMode::Spin)]
+    #[case::blocking(LatchMode::Blocking)]
+    fn test_concurrent_insert(#[case] latch_mode: LatchMode) {
+        let storage = Arc::new(InMemStorage::with_latch_mode(latch_mode));
+        let (db_id, c_id) = setup_table(&storage, ContainerType::BTree);
         let num_threads = 4;
         let num_keys_per_thread = 10000;
         let mut threads = Vec::with_capacity(num_threads);
         for i in 0..num_threads {
             let storage = storage.clone();
-            let db_id = db_id.clone();
-            let c_id = c_id.clone();
             threads.push(thread::spawn(move || {
                 for k in 0..num_keys_per_thread {
                     let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
@@ -162,6 +1316,247 @@ mod tests {
         assert_eq!(count, num_threads * num_keys_per_thread);
     }
 
+    #[rstest]
+    #[case::hash(ContainerType::Hash)]
+    #[case::btree(ContainerType::BTree)]
+    #[case::concurrent_hash(ContainerType::ConcurrentHash)]
+    #[case::skiplist(ContainerType::SkipList)]
+    #[case::art(ContainerType::Art)]
+    fn test_concurrent_delete_and_get(#[case] c_type: ContainerType) {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, c_type);
+        let num_keys = 10000usize;
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        for k in 0..num_keys {
+            let key = k.to_be_bytes().to_vec();
+            storage.insert_value(&txn, &c_id, key.clone(), key).unwrap();
+        }
+        storage.commit_txn(&txn, false).unwrap();
+
+        thread::scope(|s| {
+            // Deleters remove disjoint halves of the keys while readers look them up
+            for i in 0..2 {
+                let storage = &storage;
+                s.spawn(move || {
+                    let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+                    for k in (i..num_keys).step_by(2) {
+                        storage.delete_value(&txn, &c_id, k.to_be_bytes()).unwrap();
+                    }
+                    storage.commit_txn(&txn, false).unwrap();
+                });
+            }
+            for _ in 0..2 {
+                let storage = &storage;
+                s.spawn(move || {
+                    let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+                    for k in 0..num_keys {
+                        let key = k.to_be_bytes().to_vec();
+                        match storage.get_value(&txn, &c_id, &key) {
+                            Ok(val) => assert_eq!(val, key),
+                            Err(status) => assert_eq!(status, Status::KeyNotFound),
+                        }
+                    }
+                    storage.commit_txn(&txn, false).unwrap();
+                });
+            }
+        });
+
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let iter_handle = storage.scan_range(&txn, &c_id, ScanOptions::new()).unwrap();
+        assert!(storage.iter_next(&iter_handle).unwrap().is_none());
+    }
+
+    #[rstest]
+    #[case::one_shard(1)]
+    #[case::eight_shards(8)]
+    fn test_sharded_container(#[case] shards: usize) {
+        let storage = get_in_mem_storage();
+        let db_id = storage.open_db(DBOptions::new("test_db")).unwrap();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let options = ContainerOptions::new("sharded", ContainerType::Hash).with_shards(shards);
+        let c_id = storage.create_container(&txn, &db_id, options).unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+
+        let num_threads = 4;
+        let num_keys_per_thread = 1000usize;
+        thread::scope(|s| {
+            for i in 0..num_threads {
+                let storage = &storage;
+                s.spawn(move || {
+                    let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+                    for k in 0..num_keys_per_thread {
+                        let key = (i * num_keys_per_thread + k).to_be_bytes().to_vec();
+                        storage.insert_value(&txn, &c_id, key.clone(), key).unwrap();
+                    }
+                    storage.commit_txn(&txn, false).unwrap();
+                });
+            }
+        });
+
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let key = 42usize.to_be_bytes();
+        assert_eq!(storage.get_value(&txn, &c_id, key), Ok(key.to_vec()));
+        storage.update_value(&txn, &c_id, key, vec![0]).unwrap();
+        assert_eq!(storage.get_value(&txn, &c_id, key), Ok(vec![0]));
+        storage.delete_value(&txn, &c_id, key).unwrap();
+        assert_eq!(
+            storage.get_value(&txn, &c_id, key).map_err(|e| e.status()),
+            Err(Status::KeyNotFound)
+        );
+
+        // The scan visits every shard
+        let iter_handle = storage.scan_range(&txn, &c_id, ScanOptions::new()).unwrap();
+        let mut keys = HashSet::new();
+        while let Some((key, val)) = storage.iter_next(&iter_handle).unwrap() {
+            assert_eq!(key, val);
+            keys.insert(key);
+        }
+        drop(iter_handle);
+        assert_eq!(keys.len(), num_threads * num_keys_per_thread - 1);
+
+        storage.truncate_container(&txn, &c_id).unwrap();
+        let iter_handle = storage.scan_range(&txn, &c_id, ScanOptions::new()).unwrap();
+        assert_eq!(storage.iter_next(&iter_handle), Ok(None));
+        drop(iter_handle);
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[test]
+    fn test_only_hash_containers_are_sharded() {
+        let storage = get_in_mem_storage();
+        let db_id = storage.open_db(DBOptions::new("test_db")).unwrap();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let options = ContainerOptions::new("ordered", ContainerType::BTree).with_shards(4);
+        assert!(storage.create_container(&txn, &db_id, options).is_err());
+        let options =
+            ContainerOptions::new("buckets", ContainerType::ConcurrentHash).with_shards(4);
+        assert!(storage.create_container(&txn, &db_id, options).is_err());
+        let options = ContainerOptions::new("empty", ContainerType::Hash).with_shards(0);
+        assert!(storage.create_container(&txn, &db_id, options).is_err());
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_hash_scan_does_not_block_writers() {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, ContainerType::ConcurrentHash);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        for i in 0..100u8 {
+            storage.insert_value(&txn, &c_id, vec![i], vec![i]).unwrap();
+        }
+        let iter_handle = storage.scan_range(&txn, &c_id, ScanOptions::new()).unwrap();
+        let mut keys = HashSet::new();
+        keys.insert(storage.iter_next(&iter_handle).unwrap().unwrap().0);
+
+        // Writers make progress while the scan is in the middle of the container
+        thread::scope(|s| {
+            s.spawn(|| {
+                let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+                storage.delete_value(&txn, &c_id, [0]).unwrap();
+                storage
+                    .insert_value(&txn, &c_id, vec![200], vec![200])
+                    .unwrap();
+                storage.update_value(&txn, &c_id, [1], vec![0]).unwrap();
+                storage.commit_txn(&txn, false).unwrap();
+            })
+            .join()
+            .unwrap();
+        });
+
+        while let Some((key, _)) = storage.iter_next(&iter_handle).unwrap() {
+            assert!(keys.insert(key), "a key is returned twice");
+        }
+        // Keys that were not touched during the scan are all returned
+        assert!((2..100u8).all(|i| keys.contains(&vec![i])));
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[test]
+    fn test_skiplist_concurrent_writers_and_scan() {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, ContainerType::SkipList);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        // Even keys exist before the scan starts
+        for k in (0..1000usize).step_by(2) {
+            let key = k.to_be_bytes().to_vec();
+            storage.insert_value(&txn, &c_id, key.clone(), key).unwrap();
+        }
+        let iter_handle = storage.scan_range(&txn, &c_id, ScanOptions::new()).unwrap();
+        let mut keys = vec![storage.iter_next(&iter_handle).unwrap().unwrap().0];
+
+        // Writers insert the odd keys while the scan is alive
+        thread::scope(|s| {
+            for i in 0..4 {
+                let storage = &storage;
+                s.spawn(move || {
+                    let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+                    for k in (2 * i + 1..1000usize).step_by(8) {
+                        let key = k.to_be_bytes().to_vec();
+                        storage.insert_value(&txn, &c_id, key.clone(), key).unwrap();
+                    }
+                    storage.commit_txn(&txn, false).unwrap();
+                });
+            }
+        });
+
+        while let Some((key, val)) = storage.iter_next(&iter_handle).unwrap() {
+            assert_eq!(key, val);
+            keys.push(key);
+        }
+        // The scan returns the keys in order, including the ones it had not passed yet
+        let keys: Vec<usize> = keys
+            .iter()
+            .map(|k| usize::from_be_bytes(k.as_slice().try_into().unwrap()))
+            .collect();
+        assert_eq!(keys, (0..1000).collect::<Vec<_>>());
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[test]
+    fn test_art_path_keys() {
+        let storage = get_in_mem_storage();
+        let (db_id, c_id) = setup_table(&storage, ContainerType::Art);
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let mut expected = Vec::new();
+        for tenant in 0..3 {
+            for row in 0..300 {
+                let key = format!("tenant{}/orders/{:05}", tenant, row).into_bytes();
+                expected.push(key.clone());
+                storage.insert_value(&txn, &c_id, key, vec![]).unwrap();
+            }
+            // A key that is a prefix of the keys of the tenant
+            let key = format!("tenant{}/orders/", tenant).into_bytes();
+            expected.push(key.clone());
+            storage.insert_value(&txn, &c_id, key, vec![]).unwrap();
+        }
+        expected.sort();
+
+        let iter_handle = storage.scan_range(&txn, &c_id, ScanOptions::new()).unwrap();
+        let mut keys = Vec::new();
+        while let Some((key, _)) = storage.iter_next(&iter_handle).unwrap() {
+            keys.push(key);
+        }
+        drop(iter_handle);
+        assert_eq!(keys, expected);
+
+        storage
+            .delete_range(&txn, &c_id, b"tenant1/".to_vec(), b"tenant2/".to_vec())
+            .unwrap();
+        assert!(storage
+            .check_value(&txn, &c_id, b"tenant0/orders/")
+            .unwrap());
+        assert!(!storage
+            .check_value(&txn, &c_id, b"tenant1/orders/")
+            .unwrap());
+        assert!(!storage
+            .check_value(&txn, &c_id, b"tenant1/orders/00042")
+            .unwrap());
+        assert!(storage
+            .check_value(&txn, &c_id, b"tenant2/orders/00042")
+            .unwrap());
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
     #[test]
     fn test_concurrent_insert_and_container_ops() {
         // Create two containers.
@@ -188,8 +1583,6 @@ mod tests {
         let mut threads = Vec::with_capacity(num_threads);
         for i in 0..num_threads {
             let storage = storage.clone();
-            let db_id = db_id.clone();
-            let c_id2 = c_id2.clone();
             threads.push(thread::spawn(move || {
                 for k in 0..num_keys_per_thread {
                     let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
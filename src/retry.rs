@@ -0,0 +1,132 @@
+use std::{collections::hash_map::RandomState, hash::BuildHasher, thread, time::Duration};
+
+use crate::prelude::*;
+
+/// Runs `f` in a transaction and commits it. If `f` or the commit fails with a retryable
+/// error (see `StorageError::is_retryable`), the transaction is aborted and retried with
+/// a fresh transaction after an exponential backoff, up to `options.max_retries()` times.
+/// Other errors abort the transaction and are returned as is.
+///
+/// `f` may run several times, so it should not have side effects outside the transaction.
+pub fn run_txn<T, F, R>(
+    storage: &T,
+    db_id: &DatabaseId,
+    options: TxnOptions,
+    mut f: F,
+) -> Result<R, StorageError>
+where
+    T: TxnStorageTrait,
+    F: FnMut(&T::TxnHandle) -> Result<R, StorageError>,
+{
+    let (mut backoff, max_backoff) = options.backoff();
+    let mut retries = 0;
+    loop {
+        // The guard aborts the transaction if f fails
+        let txn = TxnGuard::begin(storage, db_id, options.clone())?;
+        let result = f(&txn).and_then(|r| txn.commit(false).map(|_| r));
+        match result {
+            Ok(r) => return Ok(r),
+            Err(e) if !e.is_retryable() || retries >= options.max_retries() => return Err(e),
+            Err(_) => {}
+        }
+        thread::sleep(jitter(backoff));
+        backoff = (backoff * 2).min(max_backoff);
+        retries += 1;
+    }
+}
+
+// A random duration in [backoff / 2, backoff], so that transactions that conflicted with
+// each other do not retry in lockstep.
+fn jitter(backoff: Duration) -> Duration {
+    let half = backoff / 2;
+    let random = RandomState::new().hash_one(0u8);
+    half + half.mul_f64((random % 1024) as f64 / 1024.0)
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    fn setup() -> (InMemStorage, DatabaseId, ContainerId) {
+        let storage = InMemStorage::new();
+        let db_id = storage.open_db(DBOptions::new("test_db")).unwrap();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let options = ContainerOptions::new("test_container", ContainerType::Hash);
+        let c_id = storage.create_container(&txn, &db_id, options).unwrap();
+        storage.commit_txn(&txn, false).unwrap();
+        (storage, db_id, c_id)
+    }
+
+    fn options() -> TxnOptions {
+        TxnOptions::default()
+            .with_max_retries(3)
+            .with_backoff(Duration::ZERO, Duration::ZERO)
+    }
+
+    #[test]
+    fn test_retries_conflicts() {
+        let (storage, db_id, c_id) = setup();
+        let mut attempts = 0;
+        let result = run_txn(&storage, &db_id, options(), |txn| {
+            attempts += 1;
+            if attempts < 3 {
+                return Err(Status::TxnConflict.into());
+            }
+            storage.insert_value(txn, &c_id, vec![0], vec![attempts])?;
+            Ok(attempts)
+        });
+        assert_eq!(result, Ok(3));
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        assert_eq!(storage.get_value(&txn, &c_id, [0]), Ok(vec![3]));
+    }
+
+    #[test]
+    fn test_retry_does_not_see_failed_attempt() {
+        let (storage, db_id, c_id) = setup();
+        let mut attempts = 0;
+        let result = run_txn(&storage, &db_id, options(), |txn| {
+            attempts += 1;
+            storage.insert_value(txn, &c_id, vec![0], vec![attempts])?;
+            if attempts == 1 {
+                return Err(Status::TxnConflict.into());
+            }
+            Ok(())
+        });
+        assert_eq!(result, Ok(()));
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        assert_eq!(storage.get_value(&txn, &c_id, [0]), Ok(vec![2]));
+    }
+
+    #[test]
+    fn test_gives_up_after_max_retries() {
+        let (storage, db_id, _) = setup();
+        let mut attempts = 0;
+        let result: Result<(), _> = run_txn(&storage, &db_id, options(), |_| {
+            attempts += 1;
+            Err(Status::SystemAbort.into())
+        });
+        assert_eq!(result.unwrap_err(), Status::SystemAbort);
+        assert_eq!(attempts, 4);
+    }
+
+    #[test]
+    fn test_does_not_retry_other_errors() {
+        let (storage, db_id, c_id) = setup();
+        let mut attempts = 0;
+        let result = run_txn(&storage, &db_id, options(), |txn| {
+            attempts += 1;
+            storage.get_value(txn, &c_id, [0])
+        });
+        assert_eq!(result.unwrap_err(), Status::KeyNotFound);
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_backoff_jitter() {
+        let backoff = Duration::from_millis(8);
+        for _ in 0..100 {
+            let d = jitter(backoff);
+            assert!(d >= backoff / 2 && d <= backoff);
+        }
+    }
+}
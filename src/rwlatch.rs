@@ -1,18 +1,94 @@
-use std::sync::atomic::{AtomicI16, Ordering};
+// Under `--cfg loom` the latch is built on loom's primitives so that the model checker
+// can explore its interleavings (see loom_tests).
+#[cfg(all(loom, debug_assertions))]
+use loom::sync::atomic::AtomicU64;
+#[cfg(loom)]
+use loom::{
+    hint::spin_loop,
+    sync::{
+        atomic::{AtomicI32, AtomicU16, Ordering},
+        Condvar, Mutex,
+    },
+};
+#[cfg(all(not(loom), debug_assertions))]
+use std::sync::atomic::AtomicU64;
+use std::{
+    cell::RefCell,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+#[cfg(not(loom))]
+use std::{
+    hint::spin_loop,
+    sync::{
+        atomic::{AtomicI32, AtomicU16, Ordering},
+        Condvar, Mutex,
+    },
+};
 
+// Number of failed attempts before a blocking latch parks the thread.
+// Loom parks right away to keep the state space small.
+#[cfg(not(loom))]
+const SPIN_LIMIT: u32 = 128;
+#[cfg(loom)]
+const SPIN_LIMIT: u32 = 1;
+// Maximum number of shared holders. Acquiring one more panics instead of wrapping the
+// counter around to a negative value, which would be taken as an exclusive holder.
+const MAX_READERS: i32 = i32::MAX;
+
+/// How a thread waits for the latch.
+/// - `Spin`: spin until the latch is available. Readers and writers are not ordered.
+/// - `Blocking`: spin for a while, then park the thread until the latch is released.
+///   Waiting writers are preferred: new readers wait while a writer is waiting,
+///   except threads that already hold the latch in shared mode, which the writer
+///   is waiting for. A thread that holds the latch must still not acquire it in
+///   exclusive mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LatchMode {
+    #[default]
+    Spin,
+    Blocking,
+}
+
+/// Reader-writer latch of a container shard.
+///
+/// There is no optimistic read mode where readers skip the counter and validate a
+/// version afterwards: the latched data are std collections, which a writer can
+/// reallocate and free under a reader, so an unlatched read is undefined behavior even
+/// if it is thrown away after failing validation. Point lookups that must not contend
+/// on a shared counter belong in containers whose parts support concurrent readers
+/// without a container latch, SkipList and ConcurrentHash.
 pub struct RwLatch {
-    pub cnt: AtomicI16,
+    pub cnt: AtomicI32,
+    mode: LatchMode,
+    writers_waiting: AtomicU16, // only used by Blocking
+    parked: AtomicU16,          // only used by Blocking
+    parking: Mutex<()>,
+    cvar: Condvar,
+    #[cfg(debug_assertions)]
+    owner: AtomicU64, // thread holding the exclusive latch, 0 if none
 }
 
 impl Default for RwLatch {
     fn default() -> Self {
-        RwLatch {
-            cnt: AtomicI16::new(0), // Up to 2^15 readers or 1 writer
-        }
+        RwLatch::new(LatchMode::default())
     }
 }
 
 impl RwLatch {
+    pub fn new(mode: LatchMode) -> Self {
+        RwLatch {
+            cnt: AtomicI32::new(0), // Up to MAX_READERS readers or 1 writer
+            mode,
+            writers_waiting: AtomicU16::new(0),
+            parked: AtomicU16::new(0),
+            parking: Mutex::new(()),
+            cvar: Condvar::new(),
+            #[cfg(debug_assertions)]
+            owner: AtomicU64::new(0),
+        }
+    }
+
     #[allow(dead_code)]
     pub fn is_locked(&self) -> bool {
         self.cnt.load(Ordering::Acquire) != 0
@@ -28,11 +104,165 @@ impl RwLatch {
         self.cnt.load(Ordering::Acquire) < 0
     }
 
-    pub fn shared(&self) {
-        let mut expected: i16;
+    /// Panics if the current thread does not hold the latch in exclusive mode.
+    /// Only checked in debug builds.
+    pub fn debug_assert_exclusive(&self) {
+        #[cfg(debug_assertions)]
+        assert!(
+            self.is_exclusive() && self.owner.load(Ordering::Relaxed) == current_thread_id(),
+            "latch is not held exclusively by the current thread"
+        );
+    }
+
+    pub fn shared(&self) -> SharedGuard<'_> {
+        self.acquire_shared(None);
+        SharedGuard::new(self)
+    }
+
+    #[allow(dead_code)]
+    pub fn try_shared(&self) -> Option<SharedGuard<'_>> {
+        self.try_acquire_shared().then(|| SharedGuard::new(self))
+    }
+
+    /// Give up if the latch cannot be acquired within the timeout.
+    #[allow(dead_code)]
+    pub fn shared_timeout(&self, timeout: Duration) -> Option<SharedGuard<'_>> {
+        self.acquire_shared(Some(Instant::now() + timeout))
+            .then(|| SharedGuard::new(self))
+    }
+
+    pub fn exclusive(&self) -> ExclusiveGuard<'_> {
+        self.acquire_exclusive(None);
+        ExclusiveGuard::new(self)
+    }
+
+    pub fn try_exclusive(&self) -> Option<ExclusiveGuard<'_>> {
+        self.try_acquire_exclusive()
+            .then(|| ExclusiveGuard::new(self))
+    }
+
+    /// Give up if the latch cannot be acquired within the timeout.
+    #[allow(dead_code)]
+    pub fn exclusive_timeout(&self, timeout: Duration) -> Option<ExclusiveGuard<'_>> {
+        self.acquire_exclusive(Some(Instant::now() + timeout))
+            .then(|| ExclusiveGuard::new(self))
+    }
+
+    // Called after the exclusive latch is acquired, before the protected data is modified.
+    fn begin_write(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.store(current_thread_id(), Ordering::Relaxed);
+    }
+
+    // Called after the protected data is modified, before the exclusive latch is released.
+    fn end_write(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.store(0, Ordering::Relaxed);
+    }
+
+    fn is_blocking(&self) -> bool {
+        self.mode == LatchMode::Blocking
+    }
+
+    // Whether a new reader can enter when the counter is cnt
+    fn can_share(&self, cnt: i32) -> bool {
+        cnt >= 0
+            && (!self.is_blocking()
+                || self.writers_waiting.load(Ordering::SeqCst) == 0
+                || self.is_held_shared())
+    }
+
+    // Whether the current thread holds this Blocking latch in shared mode
+    fn is_held_shared(&self) -> bool {
+        let addr = self as *const Self as usize;
+        SHARED_HOLDS.with(|holds| holds.borrow().iter().any(|(latch, _)| *latch == addr))
+    }
+
+    fn add_shared_hold(&self) {
+        if !self.is_blocking() {
+            return;
+        }
+        let addr = self as *const Self as usize;
+        SHARED_HOLDS.with(|holds| {
+            let mut holds = holds.borrow_mut();
+            match holds.iter_mut().find(|(latch, _)| *latch == addr) {
+                Some((_, count)) => *count += 1,
+                None => holds.push((addr, 1)),
+            }
+        });
+    }
+
+    // Shared guards are not Send, so the hold is removed by the thread that added it.
+    fn remove_shared_hold(&self) {
+        if !self.is_blocking() {
+            return;
+        }
+        let addr = self as *const Self as usize;
+        SHARED_HOLDS.with(|holds| {
+            let mut holds = holds.borrow_mut();
+            if let Some(i) = holds.iter().position(|(latch, _)| *latch == addr) {
+                holds[i].1 -= 1;
+                if holds[i].1 == 0 {
+                    holds.swap_remove(i);
+                }
+            }
+        });
+    }
+
+    // Wait for a release or until the deadline. Returns immediately if ready() holds,
+    // which is checked after registering as parked so that a release is never missed.
+    fn park(&self, deadline: Option<Instant>, ready: impl Fn(i32) -> bool) {
+        let guard = self.parking.lock().unwrap();
+        self.parked.fetch_add(1, Ordering::AcqRel);
+        if !ready(self.cnt.load(Ordering::Acquire)) {
+            match deadline {
+                None => drop(self.cvar.wait(guard).unwrap()),
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    drop(self.cvar.wait_timeout(guard, timeout).unwrap());
+                }
+            }
+        }
+        self.parked.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    // The read-modify-write on parked pairs with the one in park(): either the parker
+    // registers first and is notified here, or it acquires this release and sees the
+    // updated counter. A plain load would need a SeqCst fence to give the same guarantee.
+    fn unpark(&self) {
+        if self.is_blocking() && self.parked.fetch_add(0, Ordering::AcqRel) > 0 {
+            let _guard = self.parking.lock().unwrap();
+            self.cvar.notify_all();
+        }
+    }
+
+    // Wait after a failed attempt. Returns false if the deadline has passed.
+    fn backoff(
+        &self,
+        spins: &mut u32,
+        deadline: Option<Instant>,
+        ready: impl Fn(i32) -> bool,
+    ) -> bool {
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            return false;
+        }
+        if self.is_blocking() && *spins >= SPIN_LIMIT {
+            self.park(deadline, ready);
+            *spins = 0;
+        } else {
+            *spins += 1;
+            spin_loop();
+        }
+        true
+    }
+
+    fn acquire_shared(&self, deadline: Option<Instant>) -> bool {
+        let mut expected: i32;
+        let mut spins = 0;
         loop {
             expected = self.cnt.load(Ordering::Acquire);
-            if expected >= 0
+            check_reader_overflow(expected);
+            if self.can_share(expected)
                 && self.cnt.compare_exchange(
                     expected,
                     expected + 1,
@@ -40,17 +270,22 @@ impl RwLatch {
                     Ordering::Acquire,
                 ) == Ok(expected)
             {
-                break;
+                self.add_shared_hold();
+                return true;
+            }
+            if !self.backoff(&mut spins, deadline, |cnt| self.can_share(cnt)) {
+                return false;
             }
-            std::hint::spin_loop();
         }
     }
 
-    pub fn try_shared(&self) -> bool {
-        let mut expected: i16;
+    #[allow(dead_code)]
+    fn try_acquire_shared(&self) -> bool {
+        let mut expected: i32;
         loop {
             expected = self.cnt.load(Ordering::Acquire);
-            if expected < 0 {
+            check_reader_overflow(expected);
+            if !self.can_share(expected) {
                 return false;
             }
             if self.cnt.compare_exchange(
@@ -60,14 +295,20 @@ impl RwLatch {
                 Ordering::Acquire,
             ) == Ok(expected)
             {
+                self.add_shared_hold();
                 return true;
             }
         }
     }
 
-    pub fn exclusive(&self) {
-        let mut expected: i16;
-        loop {
+    fn acquire_exclusive(&self, deadline: Option<Instant>) -> bool {
+        if self.is_blocking() {
+            // Stop new readers from entering while waiting
+            self.writers_waiting.fetch_add(1, Ordering::SeqCst);
+        }
+        let mut expected: i32;
+        let mut spins = 0;
+        let acquired = loop {
             expected = self.cnt.load(Ordering::Acquire);
             if expected == 0
                 && self
@@ -75,14 +316,24 @@ impl RwLatch {
                     .compare_exchange(expected, -1, Ordering::AcqRel, Ordering::Acquire)
                     == Ok(expected)
             {
-                break;
+                break true;
+            }
+            if !self.backoff(&mut spins, deadline, |cnt| cnt == 0) {
+                break false;
+            }
+        };
+        if self.is_blocking() {
+            self.writers_waiting.fetch_sub(1, Ordering::SeqCst);
+            if !acquired {
+                // Readers may have been waiting for this writer
+                self.unpark();
             }
-            std::hint::spin_loop();
         }
+        acquired
     }
 
-    pub fn try_exclusive(&self) -> bool {
-        let mut expected: i16;
+    fn try_acquire_exclusive(&self) -> bool {
+        let mut expected: i32;
         loop {
             expected = self.cnt.load(Ordering::Acquire);
             if expected != 0 {
@@ -98,25 +349,39 @@ impl RwLatch {
         }
     }
 
-    #[allow(dead_code)]
-    pub fn upgrade(&self) {
-        let mut expected: i16;
-        loop {
-            expected = self.cnt.load(Ordering::Acquire);
-            if expected == 1
-                && self
-                    .cnt
-                    .compare_exchange(expected, -1, Ordering::AcqRel, Ordering::Acquire)
-                    == Ok(expected)
+    // Waits until the caller is the only shared holder, like a writer: Blocking latches
+    // stop new readers and park the caller. Returns false if the deadline has passed.
+    fn upgrade(&self, deadline: Option<Instant>) -> bool {
+        if self.is_blocking() {
+            self.writers_waiting.fetch_add(1, Ordering::SeqCst);
+        }
+        let mut spins = 0;
+        let upgraded = loop {
+            if self
+                .cnt
+                .compare_exchange(1, -1, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
             {
-                break;
+                break true;
             }
-            std::hint::spin_loop();
+            if !self.backoff(&mut spins, deadline, |cnt| cnt == 1) {
+                break false;
+            }
+        };
+        if self.is_blocking() {
+            self.writers_waiting.fetch_sub(1, Ordering::SeqCst);
+            if !upgraded {
+                self.unpark();
+            }
+        }
+        if upgraded {
+            self.remove_shared_hold();
         }
+        upgraded
     }
 
-    pub fn try_upgrade(&self) -> bool {
-        let mut expected: i16;
+    fn try_upgrade(&self) -> bool {
+        let mut expected: i32;
         loop {
             expected = self.cnt.load(Ordering::Acquire);
             if expected != 1 {
@@ -127,38 +392,167 @@ impl RwLatch {
                 .compare_exchange(expected, -1, Ordering::AcqRel, Ordering::Acquire)
                 == Ok(expected)
             {
+                self.remove_shared_hold();
                 return true;
             }
         }
     }
 
-    pub fn downgrade(&self) {
-        let mut expected: i16;
-        loop {
-            expected = self.cnt.load(Ordering::Acquire);
-            if expected == -1
-                && self
-                    .cnt
-                    .compare_exchange(expected, 1, Ordering::AcqRel, Ordering::Acquire)
-                    == Ok(expected)
-            {
-                break;
-            }
-            std::hint::spin_loop();
-        }
+    // The caller holds the latch exclusively, so no one else can change the counter.
+    fn downgrade(&self) {
+        let prev = self.cnt.swap(1, Ordering::AcqRel);
+        debug_assert_eq!(prev, -1, "downgrade without holding the latch exclusively");
+        self.add_shared_hold();
+        self.unpark();
     }
 
-    pub fn release_shared(&self) {
-        self.cnt.fetch_sub(1, Ordering::Release);
+    // Wakes the writers when the last holder leaves, and the upgraders when a single
+    // holder is left.
+    fn release_shared(&self) {
+        self.remove_shared_hold();
+        if matches!(self.cnt.fetch_sub(1, Ordering::Release), 1 | 2) {
+            self.unpark();
+        }
     }
 
-    pub fn release_exclusive(&self) {
+    fn release_exclusive(&self) {
         self.cnt.store(0, Ordering::Release);
+        self.unpark();
     }
 }
 
-#[cfg(test)]
+// Leaked shared guards (e.g. forgotten iterators) are the usual cause of this.
+fn check_reader_overflow(cnt: i32) {
+    assert!(
+        cnt < MAX_READERS,
+        "RwLatch reader count overflow: {} shared holders",
+        MAX_READERS
+    );
+}
+
+// Shared holds of Blocking latches by the current thread: latch address and count.
+#[cfg(not(loom))]
+std::thread_local!(static SHARED_HOLDS: RefCell<Vec<(usize, u32)>> = const { RefCell::new(Vec::new()) });
+#[cfg(loom)]
+loom::thread_local!(static SHARED_HOLDS: RefCell<Vec<(usize, u32)>> = RefCell::new(Vec::new()));
+
+// Identifies the current thread for the debug ownership checks. Never 0.
+#[cfg(debug_assertions)]
+fn current_thread_id() -> u64 {
+    static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+    #[cfg(not(loom))]
+    std::thread_local!(static ID: u64 = NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    #[cfg(loom)]
+    loom::thread_local!(static ID: u64 = NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    ID.with(|id| *id)
+}
+
+/// Shared latch held until the guard is dropped.
+/// The guard is not `Send`: the latch is released by the thread that acquired it, which
+/// keeps the shared holds of Blocking latches recorded per thread accurate.
+#[must_use = "the latch is released when the guard is dropped"]
+pub struct SharedGuard<'a> {
+    latch: &'a RwLatch,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a> SharedGuard<'a> {
+    fn new(latch: &'a RwLatch) -> Self {
+        SharedGuard {
+            latch,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Wait until this is the only shared holder and convert to exclusive.
+    /// Two holders upgrading at the same time wait for each other forever.
+    #[allow(dead_code)]
+    pub fn upgrade(self) -> ExclusiveGuard<'a> {
+        let latch = self.latch;
+        std::mem::forget(self);
+        latch.upgrade(None);
+        ExclusiveGuard::new(latch)
+    }
+
+    /// Give the shared latch back if the other holders do not leave within the timeout.
+    #[allow(dead_code)]
+    pub fn upgrade_timeout(self, timeout: Duration) -> Result<ExclusiveGuard<'a>, Self> {
+        if self.latch.upgrade(Some(Instant::now() + timeout)) {
+            let latch = self.latch;
+            std::mem::forget(self);
+            Ok(ExclusiveGuard::new(latch))
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Convert to exclusive if this is the only shared holder.
+    #[allow(dead_code)]
+    pub fn try_upgrade(self) -> Result<ExclusiveGuard<'a>, Self> {
+        if self.latch.try_upgrade() {
+            let latch = self.latch;
+            std::mem::forget(self);
+            Ok(ExclusiveGuard::new(latch))
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl Drop for SharedGuard<'_> {
+    fn drop(&mut self) {
+        self.latch.release_shared();
+    }
+}
+
+/// Exclusive latch held until the guard is dropped.
+/// The guard is not `Send`: the latch is released by the thread that acquired it.
+#[must_use = "the latch is released when the guard is dropped"]
+pub struct ExclusiveGuard<'a> {
+    latch: &'a RwLatch,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a> ExclusiveGuard<'a> {
+    fn new(latch: &'a RwLatch) -> Self {
+        latch.begin_write();
+        ExclusiveGuard {
+            latch,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Panics if this guard does not hold `latch`.
+    /// Debug builds also check that the latch is still held by the current thread.
+    pub fn assert_holds(&self, latch: &RwLatch) {
+        assert!(
+            std::ptr::eq(self.latch, latch),
+            "exclusive guard belongs to another latch"
+        );
+        latch.debug_assert_exclusive();
+    }
+
+    /// Convert to shared without letting a writer in between.
+    #[allow(dead_code)]
+    pub fn downgrade(self) -> SharedGuard<'a> {
+        let latch = self.latch;
+        std::mem::forget(self);
+        latch.end_write();
+        latch.downgrade();
+        SharedGuard::new(latch)
+    }
+}
+
+impl Drop for ExclusiveGuard<'_> {
+    fn drop(&mut self) {
+        self.latch.end_write();
+        self.latch.release_exclusive();
+    }
+}
+
+#[cfg(all(test, not(loom)))]
 mod tests {
+    use rstest::rstest;
     use std::{cell::UnsafeCell, thread};
 
     use super::*;
@@ -191,8 +585,14 @@ mod tests {
 
     impl Default for RwLatchProtectedCounter {
         fn default() -> Self {
+            RwLatchProtectedCounter::new(LatchMode::default())
+        }
+    }
+
+    impl RwLatchProtectedCounter {
+        pub fn new(mode: LatchMode) -> Self {
             RwLatchProtectedCounter {
-                rwlatch: RwLatch::default(),
+                rwlatch: RwLatch::new(mode),
                 counter: Counter::new(),
             }
         }
@@ -200,15 +600,16 @@ mod tests {
 
     unsafe impl Sync for RwLatchProtectedCounter {}
 
-    #[test]
-    fn test_multiple_writers_consistency() {
-        let counter = RwLatchProtectedCounter::default();
+    #[rstest]
+    #[case::spin(LatchMode::Spin)]
+    #[case::blocking(LatchMode::Blocking)]
+    fn test_multiple_writers_consistency(#[case] mode: LatchMode) {
+        let counter = RwLatchProtectedCounter::new(mode);
         thread::scope(|s| {
             for _ in 0..10000 {
                 s.spawn(|| {
-                    counter.rwlatch.exclusive();
+                    let _guard = counter.rwlatch.exclusive();
                     counter.counter.increment();
-                    counter.rwlatch.release_exclusive();
                 });
             }
         });
@@ -221,11 +622,337 @@ mod tests {
         thread::scope(|s| {
             for _ in 0..1000 {
                 s.spawn(|| {
-                    counter.rwlatch.shared();
+                    let _guard = counter.rwlatch.shared();
                     assert_eq!(counter.counter.read(), 0);
                 });
             }
         });
         assert_eq!(counter.counter.read(), 0);
     }
+
+    #[test]
+    fn test_guards_release_on_drop() {
+        let latch = RwLatch::default();
+        {
+            let _g1 = latch.shared();
+            let _g2 = latch.try_shared().unwrap();
+            assert!(latch.is_shared());
+            assert!(latch.try_exclusive().is_none());
+        }
+        assert!(!latch.is_locked());
+        {
+            let _g = latch.exclusive();
+            assert!(latch.is_exclusive());
+            assert!(latch.try_shared().is_none());
+        }
+        assert!(!latch.is_locked());
+    }
+
+    #[test]
+    fn test_guards_release_on_panic() {
+        let latch = RwLatch::default();
+        let result = std::panic::catch_unwind(|| {
+            let _guard = latch.exclusive();
+            panic!("panic while holding the latch");
+        });
+        assert!(result.is_err());
+        assert!(!latch.is_locked());
+    }
+
+    #[test]
+    fn test_upgrade_and_downgrade() {
+        let latch = RwLatch::default();
+        let g1 = latch.shared();
+        let g2 = latch.shared();
+        // Cannot upgrade while another reader holds the latch
+        let g1 = g1.try_upgrade().err().unwrap();
+        drop(g2);
+        let exclusive = g1.try_upgrade().ok().unwrap();
+        assert!(latch.is_exclusive());
+        let shared = exclusive.downgrade();
+        assert!(latch.is_shared());
+        let exclusive = shared.upgrade();
+        assert!(latch.is_exclusive());
+        drop(exclusive);
+        assert!(!latch.is_locked());
+    }
+
+    #[rstest]
+    #[case::spin(LatchMode::Spin)]
+    #[case::blocking(LatchMode::Blocking)]
+    fn test_readers_and_writers_consistency(#[case] mode: LatchMode) {
+        let counter = RwLatchProtectedCounter::new(mode);
+        thread::scope(|s| {
+            for i in 0..1000 {
+                let counter = &counter;
+                s.spawn(move || {
+                    if i % 4 == 0 {
+                        let _guard = counter.rwlatch.exclusive();
+                        counter.counter.increment();
+                    } else {
+                        let _guard = counter.rwlatch.shared();
+                        assert!(counter.counter.read() <= 250);
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.counter.read(), 250);
+    }
+
+    #[test]
+    fn test_blocking_latch_prefers_writers() {
+        let latch = RwLatch::new(LatchMode::Blocking);
+        let reader = latch.shared();
+        thread::scope(|s| {
+            let writer = s.spawn(|| {
+                let _guard = latch.exclusive();
+            });
+            while latch.writers_waiting.load(Ordering::SeqCst) == 0 {
+                std::hint::spin_loop();
+            }
+            // A new reader cannot overtake the waiting writer
+            s.spawn(|| {
+                assert!(latch.try_shared().is_none());
+                assert!(latch.shared_timeout(Duration::from_millis(10)).is_none());
+            })
+            .join()
+            .unwrap();
+            // The writer waits for the reader, so the reader can take the latch again
+            drop(latch.try_shared().unwrap());
+            drop(latch.shared());
+            drop(reader);
+            writer.join().unwrap();
+        });
+        assert!(!latch.is_locked());
+        assert!(latch.try_shared().is_some());
+    }
+
+    #[rstest]
+    #[case::spin(LatchMode::Spin)]
+    #[case::blocking(LatchMode::Blocking)]
+    fn test_upgrade_timeout(#[case] mode: LatchMode) {
+        let latch = RwLatch::new(mode);
+        let other = latch.shared();
+        let guard = latch.shared();
+        let guard = guard
+            .upgrade_timeout(Duration::from_millis(10))
+            .err()
+            .unwrap();
+        thread::scope(|s| {
+            let upgrader = s.spawn(|| {
+                let exclusive = latch.shared().upgrade_timeout(Duration::from_secs(10));
+                assert!(exclusive.is_ok());
+            });
+            // The upgrader waits for both holders to leave
+            thread::sleep(Duration::from_millis(10));
+            drop(guard);
+            drop(other);
+            upgrader.join().unwrap();
+        });
+        assert!(!latch.is_locked());
+    }
+
+    #[rstest]
+    #[case::spin(LatchMode::Spin)]
+    #[case::blocking(LatchMode::Blocking)]
+    fn test_acquire_timeout(#[case] mode: LatchMode) {
+        let latch = RwLatch::new(mode);
+        let timeout = Duration::from_millis(10);
+        let writer = latch.exclusive();
+        assert!(latch.shared_timeout(timeout).is_none());
+        assert!(latch.exclusive_timeout(timeout).is_none());
+        drop(writer);
+
+        let reader = latch.shared();
+        assert!(latch.exclusive_timeout(timeout).is_none());
+        // A writer that timed out does not block readers anymore
+        assert!(latch.shared_timeout(timeout).is_some());
+        drop(reader);
+        assert!(latch.exclusive_timeout(timeout).is_some());
+        assert!(!latch.is_locked());
+    }
+
+    #[test]
+    fn test_exclusive_owner_check() {
+        let latch = RwLatch::default();
+        let guard = latch.exclusive();
+        guard.assert_holds(&latch);
+        let downgraded = guard.downgrade();
+        drop(downgraded.try_upgrade().ok().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "another latch")]
+    fn test_exclusive_guard_of_another_latch() {
+        let latch = RwLatch::default();
+        let other = RwLatch::default();
+        latch.exclusive().assert_holds(&other);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "not held exclusively")]
+    fn test_shared_latch_fails_exclusive_check() {
+        let latch = RwLatch::default();
+        let _guard = latch.shared();
+        latch.debug_assert_exclusive();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "not held exclusively")]
+    fn test_latch_held_by_another_thread_fails_exclusive_check() {
+        let latch = &RwLatch::default();
+        thread::scope(|s| {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+            s.spawn(move || {
+                let _guard = latch.exclusive();
+                tx.send(()).unwrap();
+                // Hold the latch until the main thread has checked it
+                let _ = done_rx.recv();
+            });
+            rx.recv().unwrap();
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                latch.debug_assert_exclusive()
+            }));
+            drop(done_tx);
+            if let Err(e) = result {
+                std::panic::resume_unwind(e);
+            }
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "reader count overflow")]
+    fn test_reader_count_overflow() {
+        // Leak shared guards until the counter is saturated. Start close to the limit
+        // instead of leaking 2^31 guards.
+        let latch = RwLatch::default();
+        latch.cnt.store(MAX_READERS - 2, Ordering::Release);
+        for _ in 0..3 {
+            std::mem::forget(latch.shared());
+        }
+    }
+
+    #[test]
+    fn test_reader_count_overflow_keeps_latch_shared() {
+        let latch = RwLatch::default();
+        latch.cnt.store(MAX_READERS, Ordering::Release);
+        let result = std::panic::catch_unwind(|| latch.try_shared().is_some());
+        assert!(result.is_err());
+        // The counter did not wrap around into exclusive mode
+        assert!(latch.is_shared());
+        assert!(latch.try_exclusive().is_none());
+    }
+}
+
+// Run with: RUSTFLAGS="--cfg loom" cargo test --release loom
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::{cell::UnsafeCell, sync::Arc, thread};
+
+    // A value protected by the latch. Loom's UnsafeCell reports any access that is not
+    // ordered by the latch as a data race.
+    struct Protected {
+        latch: RwLatch,
+        value: UnsafeCell<u64>,
+    }
+
+    unsafe impl Sync for Protected {}
+
+    impl Protected {
+        fn new(mode: LatchMode) -> Arc<Self> {
+            Arc::new(Protected {
+                latch: RwLatch::new(mode),
+                value: UnsafeCell::new(0),
+            })
+        }
+
+        fn read(&self) -> u64 {
+            self.value.with(|v| unsafe { *v })
+        }
+
+        fn increment(&self) {
+            self.value.with_mut(|v| unsafe { *v += 1 });
+        }
+    }
+
+    fn model<F: Fn() + Sync + Send + 'static>(f: F) {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(f);
+    }
+
+    #[test]
+    fn loom_exclusive_writers() {
+        for mode in [LatchMode::Spin, LatchMode::Blocking] {
+            model(move || {
+                let p = Protected::new(mode);
+                let writer = {
+                    let p = p.clone();
+                    thread::spawn(move || {
+                        let _guard = p.latch.exclusive();
+                        p.increment();
+                    })
+                };
+                {
+                    let _guard = p.latch.exclusive();
+                    p.increment();
+                }
+                writer.join().unwrap();
+                let _guard = p.latch.shared();
+                assert_eq!(p.read(), 2);
+            });
+        }
+    }
+
+    #[test]
+    fn loom_shared_and_exclusive() {
+        for mode in [LatchMode::Spin, LatchMode::Blocking] {
+            model(move || {
+                let p = Protected::new(mode);
+                let reader = {
+                    let p = p.clone();
+                    thread::spawn(move || {
+                        let _guard = p.latch.shared();
+                        assert!(p.read() <= 1);
+                    })
+                };
+                {
+                    let _guard = p.latch.exclusive();
+                    p.increment();
+                }
+                reader.join().unwrap();
+                assert!(!p.latch.is_locked());
+            });
+        }
+    }
+
+    #[test]
+    fn loom_try_upgrade_and_downgrade() {
+        model(|| {
+            let p = Protected::new(LatchMode::Spin);
+            let upgrader = {
+                let p = p.clone();
+                thread::spawn(move || match p.latch.shared().try_upgrade() {
+                    Ok(guard) => {
+                        p.increment();
+                        let _guard = guard.downgrade();
+                        assert!(p.read() >= 1);
+                        true
+                    }
+                    Err(_guard) => false,
+                })
+            };
+            {
+                let _guard = p.latch.exclusive();
+                p.increment();
+            }
+            let upgraded = upgrader.join().unwrap();
+            let _guard = p.latch.shared();
+            assert_eq!(p.read(), if upgraded { 2 } else { 1 });
+        });
+    }
 }
@@ -1,6 +1,11 @@
-use std::collections::HashSet;
-
-#[derive(Debug, PartialEq)]
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Status {
     // Not found
     DBNotFound,
@@ -18,31 +23,196 @@ pub enum Status {
 
     // System errors
     SystemAbort,
+    IoError,
 
     // Other errors
     Error,
 }
 
+impl Status {
+    /// Whether the operation can succeed if the transaction is retried.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Status::TxnConflict | Status::SystemAbort)
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Status::DBNotFound => "DB not found",
+            Status::ContainerNotFound => "Container not found",
+            Status::TxNotFound => "Tx not found",
+            Status::KeyNotFound => "Key not found",
+            Status::DBExists => "DB already exists",
+            Status::ContainerExists => "Container already exists",
+            Status::KeyExists => "Key already exists",
+            Status::TxnConflict => "Txn conflict",
+            Status::SystemAbort => "System abort",
+            Status::IoError => "I/O error",
+            Status::Error => "Error",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for Status {}
+
 // To String conversion
 impl From<Status> for String {
     fn from(status: Status) -> String {
-        match status {
-            Status::DBNotFound => "DB not found".to_string(),
-            Status::ContainerNotFound => "Container not found".to_string(),
-            Status::TxNotFound => "Tx not found".to_string(),
-            Status::KeyNotFound => "Key not found".to_string(),
-            Status::DBExists => "DB already exists".to_string(),
-            Status::ContainerExists => "Container already exists".to_string(),
-            Status::KeyExists => "Key already exists".to_string(),
-            Status::TxnConflict => "Txn conflict".to_string(),
-            Status::SystemAbort => "System abort".to_string(),
-            Status::Error => "Error".to_string(),
+        status.to_string()
+    }
+}
+
+/// Error returned by the storage: the `Status` of the failure, the container, key and
+/// transaction involved when they are known, and the underlying error if any.
+#[derive(Debug)]
+pub struct StorageError {
+    status: Status,
+    c_id: Option<ContainerId>,
+    key: Option<Vec<u8>>,
+    txn_id: Option<TxnId>,
+    source: Option<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+impl StorageError {
+    pub fn new(status: Status) -> Self {
+        StorageError {
+            status,
+            c_id: None,
+            key: None,
+            txn_id: None,
+            source: None,
         }
     }
+
+    pub fn with_container(mut self, c_id: ContainerId) -> Self {
+        self.c_id = Some(c_id);
+        self
+    }
+
+    pub fn with_key(mut self, key: &[u8]) -> Self {
+        self.key = Some(key.to_vec());
+        self
+    }
+
+    pub fn with_txn(mut self, txn_id: TxnId) -> Self {
+        self.txn_id = Some(txn_id);
+        self
+    }
+
+    pub fn with_source(
+        mut self,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn container_id(&self) -> Option<ContainerId> {
+        self.c_id
+    }
+
+    pub fn key(&self) -> Option<&[u8]> {
+        self.key.as_deref()
+    }
+
+    pub fn txn_id(&self) -> Option<TxnId> {
+        self.txn_id
+    }
+
+    /// Whether the operation can succeed if it is retried: transaction conflicts, system
+    /// aborts and transient I/O errors (interrupted, would block or timed out).
+    pub fn is_retryable(&self) -> bool {
+        if self.status.is_retryable() {
+            return true;
+        }
+        let io_error = self
+            .source
+            .as_ref()
+            .and_then(|e| e.downcast_ref::<std::io::Error>());
+        io_error.is_some_and(|e| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::TimedOut
+            )
+        })
+    }
+}
+
+// e.g. Key not found (container 1, key "user/42", txn 7): <source>
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.status)?;
+        let mut context = Vec::new();
+        if let Some(c_id) = self.c_id {
+            context.push(format!("container {}", c_id));
+        }
+        if let Some(key) = &self.key {
+            context.push(format!("key \"{}\"", key.escape_ascii()));
+        }
+        if let Some(txn_id) = self.txn_id {
+            context.push(format!("txn {}", txn_id));
+        }
+        if !context.is_empty() {
+            write!(f, " ({})", context.join(", "))?;
+        }
+        if let Some(source) = &self.source {
+            write!(f, ": {}", source)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl From<Status> for StorageError {
+    fn from(status: Status) -> Self {
+        StorageError::new(status)
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        StorageError::new(Status::IoError).with_source(err)
+    }
+}
+
+// Sources are compared by their message.
+impl PartialEq for StorageError {
+    fn eq(&self, other: &Self) -> bool {
+        self.status == other.status
+            && self.c_id == other.c_id
+            && self.key == other.key
+            && self.txn_id == other.txn_id
+            && self.source.as_ref().map(|e| e.to_string())
+                == other.source.as_ref().map(|e| e.to_string())
+    }
+}
+
+impl PartialEq<Status> for StorageError {
+    fn eq(&self, status: &Status) -> bool {
+        self.status == *status
+    }
 }
 
 pub type DatabaseId = u16;
 pub type ContainerId = u16;
+pub type TxnId = u64;
+pub type SavepointId = u32;
+pub type ChangePosition = u64;
 
 pub struct DBOptions {
     name: String,
@@ -64,11 +234,32 @@ impl DBOptions {
 pub enum ContainerType {
     Hash,
     BTree,
+    // Hash container for concurrent access, backed by a hash table with a lock per bucket
+    // that grows without stopping writers. Its scans copy one bucket at a time and do not
+    // block writers. A scan sees each key at most once, in the state it had when the scan
+    // reached its bucket.
+    ConcurrentHash,
+    // Ordered container for concurrent access, backed by a lock-free skiplist with a
+    // lock per key. Its scans do not block writers and return the keys in order; a key
+    // written during a scan is returned if the scan has not passed it yet.
+    SkipList,
+    // Ordered container backed by an adaptive radix tree. Suited to long keys that share
+    // prefixes (e.g. tenant/table/row paths): a shared prefix is stored and compared once,
+    // and its nodes only take the space of the children they have.
+    Art,
 }
 
+// Combines the existing value (None if the key does not exist) with the
+// merge operands, oldest first, and returns the new value.
+pub type MergeOperator = Arc<dyn Fn(Option<&[u8]>, &[Vec<u8>]) -> Vec<u8> + Send + Sync>;
+
 pub struct ContainerOptions {
     name: String,
     c_type: ContainerType,
+    merge_operator: Option<MergeOperator>,
+    shards: Option<usize>,
+    version_retention: Option<Duration>,
+    change_capture: bool,
 }
 
 impl ContainerOptions {
@@ -76,9 +267,40 @@ impl ContainerOptions {
         ContainerOptions {
             name: String::from(name),
             c_type,
+            merge_operator: None,
+            shards: None,
+            version_retention: None,
+            change_capture: false,
         }
     }
 
+    /// Split a Hash container into `shards` parts by key hash. Each shard has its own
+    /// latch, so writers to different shards do not block each other.
+    pub fn with_shards(mut self, shards: usize) -> Self {
+        self.shards = Some(shards);
+        self
+    }
+
+    pub fn with_merge_operator(mut self, merge_operator: MergeOperator) -> Self {
+        self.merge_operator = Some(merge_operator);
+        self
+    }
+
+    /// Keep the past values of the keys for `retention`, so that transactions begun with
+    /// `TxnOptions::with_read_time` can read the container as of a time within it. The
+    /// writes to the container are serialized while it keeps versions.
+    pub fn with_version_retention(mut self, retention: Duration) -> Self {
+        self.version_retention = Some(retention);
+        self
+    }
+
+    /// Publish the committed changes of the container to the change subscribers of the
+    /// storage.
+    pub fn with_change_capture(mut self) -> Self {
+        self.change_capture = true;
+        self
+    }
+
     pub fn name(&self) -> &String {
         &self.name
     }
@@ -86,10 +308,218 @@ impl ContainerOptions {
     pub fn get_type(&self) -> ContainerType {
         self.c_type.clone()
     }
+
+    pub fn merge_operator(&self) -> Option<MergeOperator> {
+        self.merge_operator.clone()
+    }
+
+    pub fn version_retention(&self) -> Option<Duration> {
+        self.version_retention
+    }
+
+    pub fn change_capture(&self) -> bool {
+        self.change_capture
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.unwrap_or(1)
+    }
 }
 
-#[derive(Default)]
-pub struct TxnOptions {}
+// Extracts the secondary key from a key-value pair of the indexed container.
+// Returns None if the pair should not be indexed.
+pub type KeyExtractor = Arc<dyn Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+pub struct IndexOptions {
+    name: String,
+    key_extractor: KeyExtractor,
+}
+
+impl IndexOptions {
+    pub fn new(name: &str, key_extractor: KeyExtractor) -> Self {
+        IndexOptions {
+            name: String::from(name),
+            key_extractor,
+        }
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn key_extractor(&self) -> KeyExtractor {
+        self.key_extractor.clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnState {
+    Active,
+    Prepared, // waiting for the outcome of a two-phase commit
+    Committed,
+    Aborted,
+}
+
+/// Snapshot of a transaction that is neither committed nor aborted.
+#[derive(Debug, Clone)]
+pub struct TxnInfo {
+    txn_id: TxnId,
+    age: Duration,
+    state: TxnState,
+    writes: usize,
+}
+
+impl TxnInfo {
+    pub fn new(txn_id: TxnId, age: Duration, state: TxnState, writes: usize) -> Self {
+        TxnInfo {
+            txn_id,
+            age,
+            state,
+            writes,
+        }
+    }
+
+    pub fn txn_id(&self) -> TxnId {
+        self.txn_id
+    }
+
+    /// Time since the transaction began.
+    pub fn age(&self) -> Duration {
+        self.age
+    }
+
+    pub fn state(&self) -> TxnState {
+        self.state
+    }
+
+    /// Number of keys written by the transaction.
+    pub fn writes(&self) -> usize {
+        self.writes
+    }
+}
+
+/// Committed change of a key. A transaction that writes a key several times produces a
+/// single change from the value before its first write to the value at its commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    position: ChangePosition,
+    c_id: ContainerId,
+    key: Vec<u8>,
+    old: Option<Vec<u8>>, // None if the key did not exist
+    new: Option<Vec<u8>>, // None if the key was deleted
+    commit_time: SystemTime,
+    txn_id: TxnId,
+}
+
+impl Change {
+    pub fn new(
+        position: ChangePosition,
+        c_id: ContainerId,
+        key: Vec<u8>,
+        old: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+        commit_time: SystemTime,
+        txn_id: TxnId,
+    ) -> Self {
+        Change {
+            position,
+            c_id,
+            key,
+            old,
+            new,
+            commit_time,
+            txn_id,
+        }
+    }
+
+    /// Position of the change in the change stream. Subscribing from the position after
+    /// it resumes the stream after this change.
+    pub fn position(&self) -> ChangePosition {
+        self.position
+    }
+
+    pub fn container_id(&self) -> ContainerId {
+        self.c_id
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn old_value(&self) -> Option<&[u8]> {
+        self.old.as_deref()
+    }
+
+    pub fn new_value(&self) -> Option<&[u8]> {
+        self.new.as_deref()
+    }
+
+    pub fn commit_time(&self) -> SystemTime {
+        self.commit_time
+    }
+
+    pub fn txn_id(&self) -> TxnId {
+        self.txn_id
+    }
+}
+
+// Retry policy of run_txn() unless set with with_max_retries() and with_backoff().
+const DEFAULT_MAX_RETRIES: u32 = 8;
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(1);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Default, Clone)]
+pub struct TxnOptions {
+    max_retries: Option<u32>,
+    backoff: Option<(Duration, Duration)>, // initial and maximum backoff
+    two_phase_commit: bool,
+    read_time: Option<SystemTime>,
+}
+
+impl TxnOptions {
+    /// Number of times `run_txn` retries a transaction that failed with a retryable error.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Backoff of `run_txn` before the first retry. It doubles on every retry up to `max`.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = Some((initial, max));
+        self
+    }
+
+    /// Begin the transaction so that it can be prepared with `prepare_txn`.
+    pub fn with_two_phase_commit(mut self) -> Self {
+        self.two_phase_commit = true;
+        self
+    }
+
+    /// Begin a read-only transaction that reads the db as of `time`. Only the containers
+    /// that keep their versions since then can be read (see
+    /// `ContainerOptions::with_version_retention`).
+    pub fn with_read_time(mut self, time: SystemTime) -> Self {
+        self.read_time = Some(time);
+        self
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries.unwrap_or(DEFAULT_MAX_RETRIES)
+    }
+
+    pub fn backoff(&self) -> (Duration, Duration) {
+        self.backoff
+            .unwrap_or((DEFAULT_INITIAL_BACKOFF, DEFAULT_MAX_BACKOFF))
+    }
+
+    pub fn two_phase_commit(&self) -> bool {
+        self.two_phase_commit
+    }
+
+    pub fn read_time(&self) -> Option<SystemTime> {
+        self.read_time
+    }
+}
 
 #[derive(Default)]
 pub struct ScanOptions {
@@ -107,13 +537,13 @@ pub trait TxnStorageTrait {
     type IteratorHandle;
 
     // Open connection with the db
-    fn open_db(&self, options: DBOptions) -> Result<DatabaseId, Status>;
+    fn open_db(&self, options: DBOptions) -> Result<DatabaseId, StorageError>;
 
     // Close connection with the db
-    fn close_db(&self, db_id: &DatabaseId) -> Result<(), Status>;
+    fn close_db(&self, db_id: &DatabaseId) -> Result<(), StorageError>;
 
     // Delete the db
-    fn delete_db(&self, db_id: &DatabaseId) -> Result<(), Status>;
+    fn delete_db(&self, db_id: &DatabaseId) -> Result<(), StorageError>;
 
     // Create a container in the db
     fn create_container(
@@ -121,7 +551,7 @@ pub trait TxnStorageTrait {
         txn: &Self::TxnHandle,
         db_id: &DatabaseId,
         options: ContainerOptions,
-    ) -> Result<ContainerId, Status>;
+    ) -> Result<ContainerId, StorageError>;
 
     // Delete a container from the db
     fn delete_container(
@@ -129,30 +559,66 @@ pub trait TxnStorageTrait {
         txn: &Self::TxnHandle,
         db_id: &DatabaseId,
         c_id: &ContainerId,
-    ) -> Result<(), Status>;
+    ) -> Result<(), StorageError>;
+
+    // Create a secondary index on a container. The index is stored as another
+    // container and is updated by every write to the indexed container.
+    // Scanning the index container yields the entries ordered by the secondary
+    // key, with the primary keys as the values.
+    fn create_index(
+        &self,
+        txn: &Self::TxnHandle,
+        db_id: &DatabaseId,
+        c_id: &ContainerId,
+        options: IndexOptions,
+    ) -> Result<ContainerId, StorageError>;
 
     // List all container names in the db
     fn list_containers(
         &self,
         txn: &Self::TxnHandle,
         db_id: &DatabaseId,
-    ) -> Result<HashSet<ContainerId>, Status>;
+    ) -> Result<HashSet<ContainerId>, StorageError>;
 
     // Begin a transaction
-    fn begin_txn(&self, db_id: &DatabaseId, options: TxnOptions)
-        -> Result<Self::TxnHandle, Status>;
+    fn begin_txn(
+        &self,
+        db_id: &DatabaseId,
+        options: TxnOptions,
+    ) -> Result<Self::TxnHandle, StorageError>;
+
+    // Begin a read-only snapshot of the db. It can be used with check_value, get_value,
+    // lookup_index and scan_range, which see the db as it was when the snapshot began,
+    // and writes with it fail. The versions kept for it are released when its handle is
+    // dropped.
+    fn begin_snapshot(&self, db_id: &DatabaseId) -> Result<Self::TxnHandle, StorageError>;
 
     // Commit a transaction
-    fn commit_txn(&self, txn: &Self::TxnHandle, async_commit: bool) -> Result<(), Status>;
+    fn commit_txn(&self, txn: &Self::TxnHandle, async_commit: bool) -> Result<(), StorageError>;
 
     // Abort a transaction
-    fn abort_txn(&self, txn: &Self::TxnHandle) -> Result<(), Status>;
+    fn abort_txn(&self, txn: &Self::TxnHandle) -> Result<(), StorageError>;
+
+    // Prepare a transaction for two-phase commit. Once prepared, the transaction can no
+    // longer write and can still be either committed or aborted.
+    fn prepare_txn(&self, txn: &Self::TxnHandle) -> Result<(), StorageError>;
+
+    // Create a savepoint in a transaction
+    fn savepoint(&self, txn: &Self::TxnHandle) -> Result<SavepointId, StorageError>;
+
+    // Undo the writes of a transaction made after the savepoint. The transaction can
+    // continue. The savepoint stays valid and the savepoints created after it are released.
+    fn rollback_to(
+        &self,
+        txn: &Self::TxnHandle,
+        savepoint: SavepointId,
+    ) -> Result<(), StorageError>;
 
     // Wait for a transaction to finish
-    fn wait_for_txn(&self, txn: &Self::TxnHandle) -> Result<(), Status>;
+    fn wait_for_txn(&self, txn: &Self::TxnHandle) -> Result<(), StorageError>;
 
     // Drop a transaction handle
-    fn drop_txn(&self, txn: Self::TxnHandle) -> Result<(), Status>;
+    fn drop_txn(&self, txn: Self::TxnHandle) -> Result<(), StorageError>;
 
     // Check if value exists
     fn check_value<K: AsRef<[u8]>>(
@@ -160,7 +626,7 @@ pub trait TxnStorageTrait {
         txn: &Self::TxnHandle,
         c_id: &ContainerId,
         key: K,
-    ) -> Result<bool, Status>;
+    ) -> Result<bool, StorageError>;
 
     // Get value
     fn get_value<K: AsRef<[u8]>>(
@@ -168,7 +634,7 @@ pub trait TxnStorageTrait {
         txn: &Self::TxnHandle,
         c_id: &ContainerId,
         key: K,
-    ) -> Result<Vec<u8>, Status>;
+    ) -> Result<Vec<u8>, StorageError>;
 
     // Insert value
     fn insert_value(
@@ -177,7 +643,18 @@ pub trait TxnStorageTrait {
         c_id: &ContainerId,
         key: Vec<u8>,
         value: Vec<u8>,
-    ) -> Result<(), Status>;
+    ) -> Result<(), StorageError>;
+
+    // Insert value that expires after ttl. Expired keys are invisible to
+    // check_value, get_value and scan_range.
+    fn insert_value_with_ttl(
+        &self,
+        txn: &Self::TxnHandle,
+        c_id: &ContainerId,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), StorageError>;
 
     // Insert values
     fn insert_values(
@@ -185,7 +662,7 @@ pub trait TxnStorageTrait {
         txn: &Self::TxnHandle,
         c_id: &ContainerId,
         kvs: Vec<(Vec<u8>, Vec<u8>)>,
-    ) -> Result<(), Status>;
+    ) -> Result<(), StorageError>;
 
     // Update value
     fn update_value<K: AsRef<[u8]>>(
@@ -194,7 +671,36 @@ pub trait TxnStorageTrait {
         c_id: &ContainerId,
         key: K,
         value: Vec<u8>,
-    ) -> Result<(), Status>;
+    ) -> Result<(), StorageError>;
+
+    // Update value and make it expire after ttl. update_value without ttl
+    // removes the expiry of the key.
+    fn update_value_with_ttl<K: AsRef<[u8]>>(
+        &self,
+        txn: &Self::TxnHandle,
+        c_id: &ContainerId,
+        key: K,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), StorageError>;
+
+    // Merge operand into value using the merge operator of the container.
+    // The operands are combined lazily when the value is read.
+    fn merge_value(
+        &self,
+        txn: &Self::TxnHandle,
+        c_id: &ContainerId,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+    ) -> Result<(), StorageError>;
+
+    // Get the primary keys whose secondary key is sec_key, in primary key order
+    fn lookup_index<K: AsRef<[u8]>>(
+        &self,
+        txn: &Self::TxnHandle,
+        idx_id: &ContainerId,
+        sec_key: K,
+    ) -> Result<Vec<Vec<u8>>, StorageError>;
 
     // Delete value
     fn delete_value<K: AsRef<[u8]>>(
@@ -202,7 +708,24 @@ pub trait TxnStorageTrait {
         txn: &Self::TxnHandle,
         c_id: &ContainerId,
         key: K,
-    ) -> Result<(), Status>;
+    ) -> Result<(), StorageError>;
+
+    // Delete all the keys in [start, end). Only supported by ordered containers: BTree,
+    // SkipList and Art.
+    fn delete_range<K: AsRef<[u8]>>(
+        &self,
+        txn: &Self::TxnHandle,
+        c_id: &ContainerId,
+        start: K,
+        end: K,
+    ) -> Result<(), StorageError>;
+
+    // Delete all the keys in the container. The container itself stays alive.
+    fn truncate_container(
+        &self,
+        txn: &Self::TxnHandle,
+        c_id: &ContainerId,
+    ) -> Result<(), StorageError>;
 
     // Scan range. While iterating, the container should be alive.
     fn scan_range(
@@ -210,11 +733,15 @@ pub trait TxnStorageTrait {
         txn: &Self::TxnHandle,
         c_id: &ContainerId,
         options: ScanOptions,
-    ) -> Result<Self::IteratorHandle, Status>;
+    ) -> Result<Self::IteratorHandle, StorageError>;
 
     // Iterate next
-    fn iter_next(&self, iter: &Self::IteratorHandle) -> Result<Option<(Vec<u8>, Vec<u8>)>, Status>;
+    #[allow(clippy::type_complexity)]
+    fn iter_next(
+        &self,
+        iter: &Self::IteratorHandle,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, StorageError>;
 
     // Drop an iterator handle.
-    fn drop_iterator_handle(&self, iter: Self::IteratorHandle) -> Result<(), Status>;
+    fn drop_iterator_handle(&self, iter: Self::IteratorHandle) -> Result<(), StorageError>;
 }
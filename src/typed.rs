@@ -0,0 +1,361 @@
+use std::marker::PhantomData;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    keys::{decode_bytes, encode_bytes},
+    prelude::*,
+};
+
+/// Order-preserving key encoding. For any two keys `a < b`, the encoding of `a`
+/// is lexicographically smaller than the encoding of `b`, so typed keys are
+/// scanned in their natural order from BTree containers. Encodings are
+/// self-delimiting, which lets tuples (composite keys) concatenate the
+/// encodings of their fields.
+///
+/// A composite key struct can implement this trait by encoding its fields in
+/// the order they should be compared, e.g. `(self.tenant, self.id).encode_key(buf)`.
+pub trait KeyCodec: Sized {
+    fn encode_key(&self, buf: &mut Vec<u8>);
+
+    /// Decode a key from the front of `bytes` and advance `bytes` past it.
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self, Status>;
+
+    fn to_key_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_key(&mut buf);
+        buf
+    }
+
+    fn from_key_bytes(mut bytes: &[u8]) -> Result<Self, Status> {
+        let key = Self::decode_key(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(Status::Error);
+        }
+        Ok(key)
+    }
+}
+
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8], Status> {
+    if bytes.len() < n {
+        return Err(Status::Error);
+    }
+    let (head, tail) = bytes.split_at(n);
+    *bytes = tail;
+    Ok(head)
+}
+
+// Unsigned integers are encoded in big-endian.
+macro_rules! impl_unsigned_key_codec {
+    ($($t:ty),*) => {
+        $(
+            impl KeyCodec for $t {
+                fn encode_key(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_be_bytes());
+                }
+
+                fn decode_key(bytes: &mut &[u8]) -> Result<Self, Status> {
+                    let b = take(bytes, std::mem::size_of::<$t>())?;
+                    Ok(<$t>::from_be_bytes(b.try_into().unwrap()))
+                }
+            }
+        )*
+    };
+}
+
+impl_unsigned_key_codec!(u8, u16, u32, u64, u128);
+
+// Signed integers are encoded in big-endian with the sign bit flipped so that
+// negative numbers come before positive numbers.
+macro_rules! impl_signed_key_codec {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl KeyCodec for $t {
+                fn encode_key(&self, buf: &mut Vec<u8>) {
+                    let flipped = (*self as $u) ^ (1 << (<$u>::BITS - 1));
+                    buf.extend_from_slice(&flipped.to_be_bytes());
+                }
+
+                fn decode_key(bytes: &mut &[u8]) -> Result<Self, Status> {
+                    let flipped = <$u>::decode_key(bytes)?;
+                    Ok((flipped ^ (1 << (<$u>::BITS - 1))) as $t)
+                }
+            }
+        )*
+    };
+}
+
+impl_signed_key_codec!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl KeyCodec for bool {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self, Status> {
+        match u8::decode_key(bytes)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Status::Error),
+        }
+    }
+}
+
+// Byte strings use the self-delimiting encoding of the keys module.
+impl KeyCodec for Vec<u8> {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        encode_bytes(self, buf);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self, Status> {
+        decode_bytes(bytes)
+    }
+}
+
+impl KeyCodec for String {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        encode_bytes(self.as_bytes(), buf);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self, Status> {
+        String::from_utf8(decode_bytes(bytes)?).map_err(|_| Status::Error)
+    }
+}
+
+macro_rules! impl_tuple_key_codec {
+    ($($name:ident),+) => {
+        impl<$($name: KeyCodec),+> KeyCodec for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_key(buf);)+
+            }
+
+            fn decode_key(bytes: &mut &[u8]) -> Result<Self, Status> {
+                Ok(($($name::decode_key(bytes)?,)+))
+            }
+        }
+    };
+}
+
+impl_tuple_key_codec!(A);
+impl_tuple_key_codec!(A, B);
+impl_tuple_key_codec!(A, B, C);
+impl_tuple_key_codec!(A, B, C, D);
+impl_tuple_key_codec!(A, B, C, D, E);
+
+fn encode_value<V: Serialize>(value: &V) -> Result<Vec<u8>, StorageError> {
+    bincode::serialize(value).map_err(|e| StorageError::new(Status::Error).with_source(e))
+}
+
+fn decode_value<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, StorageError> {
+    bincode::deserialize(bytes).map_err(|e| StorageError::new(Status::Error).with_source(e))
+}
+
+// Undecodable scanned entries are reported with their key.
+fn decode_entry<K: KeyCodec, V: DeserializeOwned>(
+    key: &[u8],
+    val: &[u8],
+) -> Result<(K, V), StorageError> {
+    let k = K::from_key_bytes(key).map_err(|status| StorageError::new(status).with_key(key))?;
+    let v = decode_value(val).map_err(|e| e.with_key(key))?;
+    Ok((k, v))
+}
+
+/// Typed view of a container. Keys are encoded with `KeyCodec` and values are
+/// encoded with serde (bincode).
+pub struct TypedContainer<'a, T: TxnStorageTrait, K, V> {
+    storage: &'a T,
+    c_id: ContainerId,
+    phantom: PhantomData<fn() -> (K, V)>,
+}
+
+impl<'a, T, K, V> TypedContainer<'a, T, K, V>
+where
+    T: TxnStorageTrait,
+    K: KeyCodec,
+    V: Serialize + DeserializeOwned,
+{
+    pub fn new(storage: &'a T, c_id: ContainerId) -> Self {
+        TypedContainer {
+            storage,
+            c_id,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn c_id(&self) -> ContainerId {
+        self.c_id
+    }
+
+    pub fn check(&self, txn: &T::TxnHandle, key: &K) -> Result<bool, StorageError> {
+        self.storage
+            .check_value(txn, &self.c_id, key.to_key_bytes())
+    }
+
+    pub fn get(&self, txn: &T::TxnHandle, key: &K) -> Result<V, StorageError> {
+        let bytes = self
+            .storage
+            .get_value(txn, &self.c_id, key.to_key_bytes())?;
+        decode_value(&bytes)
+    }
+
+    pub fn insert(&self, txn: &T::TxnHandle, key: &K, value: &V) -> Result<(), StorageError> {
+        self.storage
+            .insert_value(txn, &self.c_id, key.to_key_bytes(), encode_value(value)?)
+    }
+
+    pub fn update(&self, txn: &T::TxnHandle, key: &K, value: &V) -> Result<(), StorageError> {
+        self.storage
+            .update_value(txn, &self.c_id, key.to_key_bytes(), encode_value(value)?)
+    }
+
+    pub fn delete(&self, txn: &T::TxnHandle, key: &K) -> Result<(), StorageError> {
+        self.storage
+            .delete_value(txn, &self.c_id, key.to_key_bytes())
+    }
+
+    pub fn scan(
+        &self,
+        txn: &T::TxnHandle,
+        options: ScanOptions,
+    ) -> Result<TypedIterator<'a, T, K, V>, StorageError> {
+        let iter = self.storage.scan_range(txn, &self.c_id, options)?;
+        Ok(TypedIterator {
+            storage: self.storage,
+            iter,
+            phantom: PhantomData,
+        })
+    }
+}
+
+pub struct TypedIterator<'a, T: TxnStorageTrait, K, V> {
+    storage: &'a T,
+    iter: T::IteratorHandle,
+    phantom: PhantomData<fn() -> (K, V)>,
+}
+
+impl<T, K, V> Iterator for TypedIterator<'_, T, K, V>
+where
+    T: TxnStorageTrait,
+    K: KeyCodec,
+    V: DeserializeOwned,
+{
+    type Item = Result<(K, V), StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.storage.iter_next(&self.iter) {
+            Ok(Some((key, val))) => Some(decode_entry(&key, &val)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::fmt::Debug;
+
+    use super::*;
+
+    fn assert_order_preserved<K: KeyCodec + Ord + Debug + Clone>(mut keys: Vec<K>) {
+        keys.sort();
+        let encoded: Vec<Vec<u8>> = keys.iter().map(|k| k.to_key_bytes()).collect();
+        let mut sorted = encoded.clone();
+        sorted.sort();
+        assert_eq!(encoded, sorted);
+        for (k, e) in keys.iter().zip(encoded.iter()) {
+            assert_eq!(&K::from_key_bytes(e).unwrap(), k);
+        }
+    }
+
+    #[test]
+    fn test_integer_key_order() {
+        assert_order_preserved(vec![0u64, 1, 255, 256, u64::MAX]);
+        assert_order_preserved(vec![i64::MIN, -256, -1, 0, 1, 255, i64::MAX]);
+        assert_order_preserved(vec![i8::MIN, -1, 0, 1, i8::MAX]);
+    }
+
+    #[test]
+    fn test_string_key_order() {
+        assert_order_preserved(vec![
+            String::new(),
+            "\0".to_string(),
+            "\0\0".to_string(),
+            "a".to_string(),
+            "a\0".to_string(),
+            "a\u{1}".to_string(),
+            "ab".to_string(),
+            "b".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_composite_key_order() {
+        assert_order_preserved(vec![
+            (-1i32, "b".to_string()),
+            (-1, "ba".to_string()),
+            (0, String::new()),
+            (0, "a".to_string()),
+            (1, "a".to_string()),
+        ]);
+        assert_order_preserved(vec![
+            ("a".to_string(), 2u16, false),
+            ("a".to_string(), 2, true),
+            ("a".to_string(), 10, false),
+            ("aa".to_string(), 0, false),
+        ]);
+    }
+
+    #[test]
+    fn test_typed_container() {
+        let storage = InMemStorage::new();
+        let db_id = storage.open_db(DBOptions::new("test_db")).unwrap();
+        let txn = storage.begin_txn(&db_id, TxnOptions::default()).unwrap();
+        let c_id = storage
+            .create_container(
+                &txn,
+                &db_id,
+                ContainerOptions::new("test_container", ContainerType::BTree),
+            )
+            .unwrap();
+        let container = TypedContainer::<_, (i64, String), Vec<u32>>::new(&storage, c_id);
+        let keys = [
+            (1, "x".to_string()),
+            (-1, "b".to_string()),
+            (-1, "a".to_string()),
+            (-300, "z".to_string()),
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            container.insert(&txn, key, &vec![i as u32; i]).unwrap();
+        }
+        container
+            .update(&txn, &(1, "x".to_string()), &vec![7])
+            .unwrap();
+        assert_eq!(container.get(&txn, &(1, "x".to_string())), Ok(vec![7]));
+        container.delete(&txn, &(-1, "b".to_string())).unwrap();
+        assert_eq!(container.check(&txn, &(-1, "b".to_string())), Ok(false));
+
+        let scanned: Vec<_> = container
+            .scan(&txn, ScanOptions::new())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            scanned,
+            vec![
+                ((-300, "z".to_string()), vec![3, 3, 3]),
+                ((-1, "a".to_string()), vec![2, 2]),
+                ((1, "x".to_string()), vec![7]),
+            ]
+        );
+        storage.commit_txn(&txn, false).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_key_bytes() {
+        assert_eq!(u32::from_key_bytes(&[0, 0, 1]), Err(Status::Error));
+        assert_eq!(u8::from_key_bytes(&[0, 0]), Err(Status::Error)); // Trailing bytes
+        assert_eq!(String::from_key_bytes(&[b'a', 0]), Err(Status::Error));
+    }
+}